@@ -0,0 +1,182 @@
+//! Compares the throughput of the channel based forwarding tcp routes used to go
+//! through against a tcp route of the liteginx binary, forwarding with `splice`
+//! at a few `TCP_BUFFER_SIZE`s, over loopback with an echo target.
+//!
+//!     cargo bench --bench tcp_forward
+
+use std::{
+    net::SocketAddr,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+const PAYLOAD: usize = 256 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+async fn echo_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+/// The pre zero-copy path: 1024 byte reads copied into a fresh `Vec` and passed
+/// through `mpsc::channel(1)` to a task writing the other socket, in both directions.
+async fn channel_proxy(target: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let upstream = TcpStream::connect(target).await.unwrap();
+            let (client_read, client_write) = client.into_split();
+            let (upstream_read, upstream_write) = upstream.into_split();
+            pump(client_read, upstream_write);
+            pump(upstream_read, client_write);
+        }
+    });
+    addr
+}
+
+fn pump(mut from: tokio::net::tcp::OwnedReadHalf, mut to: tokio::net::tcp::OwnedWriteHalf) {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1);
+    tokio::spawn(async move {
+        let mut buffer = vec![0; 1024];
+        loop {
+            match from.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(buffer[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if to.write_all(&msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown().await;
+    });
+}
+
+/// A liteginx process serving a tcp route to `target`, stopped when dropped.
+struct Liteginx {
+    child: Child,
+    conf_dir: std::path::PathBuf,
+    addr: SocketAddr,
+}
+
+impl Liteginx {
+    async fn start(target: SocketAddr, buffer_size: usize) -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let conf_dir = std::env::temp_dir().join(format!("liteginx-bench-{}", port));
+        std::fs::create_dir_all(&conf_dir).unwrap();
+        std::fs::write(
+            conf_dir.join("bench.yaml"),
+            format!(
+                "name: bench\nspec:\n- kind: tcp\n  listen: {}\n  targets: [{{host: 127.0.0.1, port: {}}}]\ntls: {{enabled: false}}\n",
+                port,
+                target.port()
+            ),
+        )
+        .unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_liteginx"))
+            .args(["listen", "--log-level", "error", "--conf-dir"])
+            .arg(&conf_dir)
+            .env("LISTEN_ADDRESS", "127.0.0.1")
+            .env("TCP_BUFFER_SIZE", buffer_size.to_string())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Self {
+            child,
+            conf_dir,
+            addr,
+        }
+    }
+}
+
+impl Drop for Liteginx {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.conf_dir);
+    }
+}
+
+async fn run(proxy: SocketAddr) -> Duration {
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    let started = Instant::now();
+    let send = tokio::spawn(async move {
+        let chunk = vec![7u8; CHUNK_SIZE];
+        let mut sent = 0;
+        while sent < PAYLOAD {
+            writer.write_all(&chunk).await.unwrap();
+            sent += chunk.len();
+        }
+        writer.shutdown().await.unwrap();
+    });
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    while received < PAYLOAD {
+        match reader.read(&mut buffer).await.unwrap() {
+            0 => break,
+            n => received += n,
+        }
+    }
+    send.await.unwrap();
+    assert_eq!(received, PAYLOAD);
+    started.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let mib = (PAYLOAD * 2) as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:<28} {:>8.2?} {:>10.1} MiB/s",
+        name,
+        elapsed,
+        mib / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let target = echo_target().await;
+    println!(
+        "forwarding {} MiB each way through an echo target",
+        PAYLOAD / (1024 * 1024)
+    );
+    let channel = channel_proxy(target).await;
+    report("mpsc channel (before)", run(channel).await);
+    for buffer_size in [8 * 1024, 64 * 1024, 256 * 1024] {
+        let liteginx = Liteginx::start(target, buffer_size).await;
+        report(
+            &format!("liteginx, {} KiB buffers", buffer_size / 1024),
+            run(liteginx.addr).await,
+        );
+    }
+}
@@ -1,27 +1,226 @@
-use crate::{pkg::listen, prelude::Result};
-use clap::{Parser, Subcommand};
+use std::{os::fd::RawFd, path::PathBuf};
+
+use crate::{
+    pkg::{conf, dry_run, explain::Probe, explain_route, listen, telemetry},
+    prelude::Result,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use config::Value;
 
 #[derive(Parser)]
-#[command(about = "lets you run various commands with the nginx proxy")]
+#[command(
+    about = "lets you run various commands with the nginx proxy",
+    arg_required_else_help = true
+)]
 struct Cmd {
+    /// settings file (YAML, TOML or JSON), overridden by environment variables
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
     #[command(subcommand)]
-    command: Option<SubCommandType>,
+    command: SubCommandType,
 }
 
 #[derive(Subcommand)]
 enum SubCommandType {
-    Listen,
+    /// serve the routes of the ingress confs
+    Listen {
+        /// take over listening sockets from a running liteginx, which then drains and exits
+        #[arg(long)]
+        upgrade: bool,
+        /// address of the admin listener [env: ADMIN_ADDR]
+        #[arg(long)]
+        admin_addr: Option<String>,
+        /// load the confs, print the routes they make and exit
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        common: Common,
+    },
+    /// explain how a request would be routed, without listening
+    Routes {
+        /// listener port the request arrives on
+        #[arg(long)]
+        port: u16,
+        /// Host header, TLS server name or forward proxy destination
+        #[arg(long)]
+        host: Option<String>,
+        #[arg(long, default_value = "GET")]
+        method: String,
+        /// request target, with its query if any
+        #[arg(long, default_value = "/")]
+        path: String,
+        /// protocol offered over TLS, for routes picking targets by SNI and ALPN
+        #[arg(long)]
+        alpn: Vec<String>,
+        #[command(flatten)]
+        common: Common,
+    },
 }
 
-pub async fn run() -> Result<()> {
-    let args = Cmd::parse();
-    match args.command {
-        Some(SubCommandType::Listen) => {
-            listen().await?;
-        }
-        None => {
-            tracing::error!("no subcommand passed");
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Json,
+    Pretty,
+}
+
+/// Flags taking precedence over settings from the environment and `--config`.
+#[derive(Args)]
+struct Common {
+    /// directory of ingress confs, searched recursively [env: LITEGINX_CONF_DIR]
+    #[arg(long)]
+    conf_dir: Option<String>,
+    /// conf file, directory or glob pattern, read after --conf-dir [env: LITEGINX_CONF_FILES]
+    #[arg(long)]
+    conf: Vec<String>,
+    /// log level or filter directives, like `info,liteginx=debug` [env: LOG_LEVEL, RUST_LOG]
+    #[arg(long)]
+    log_level: Option<String>,
+    /// how log lines are written [env: LOG_FORMAT]
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+}
+
+impl Common {
+    fn overrides(&self) -> Vec<(&'static str, Value)> {
+        let log_format = self.log_format.map(|format| match format {
+            LogFormat::Json => "json",
+            LogFormat::Pretty => "pretty",
+        });
+        let conf = (!self.conf.is_empty()).then(|| Value::from(self.conf.clone()));
+        [
+            ("liteginx_conf_dir", self.conf_dir.clone().map(Value::from)),
+            ("liteginx_conf_files", conf),
+            ("log_level", self.log_level.clone().map(Value::from)),
+            ("log_format", log_format.map(Value::from)),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect()
+    }
+}
+
+impl Cmd {
+    /// Settings given as flags to the subcommand.
+    fn overrides(&self) -> Vec<(&'static str, Value)> {
+        match self.command {
+            SubCommandType::Listen {
+                ref admin_addr,
+                ref common,
+                ..
+            } => {
+                let mut overrides = common.overrides();
+                if let Some(addr) = admin_addr {
+                    overrides.push(("admin_addr", Value::from(addr.clone())));
+                }
+                overrides
+            }
+            SubCommandType::Routes { ref common, .. } => common.overrides(),
         }
     }
-    Ok(())
+}
+
+/// Runs the command given on the command line. `systemd_fds` is the number of
+/// sockets systemd passed, which only `listen` serves.
+pub async fn run(systemd_fds: RawFd) -> Result<()> {
+    let args = Cmd::parse();
+    conf::init(args.config.as_deref(), args.overrides())?;
+    let provider = telemetry::init()?;
+    let r = match args.command {
+        SubCommandType::Listen { dry_run: true, .. } => dry_run(),
+        SubCommandType::Listen { upgrade, .. } => listen(upgrade, systemd_fds).await,
+        SubCommandType::Routes {
+            port,
+            ref host,
+            ref method,
+            ref path,
+            ref alpn,
+            ..
+        } => explain_route(&Probe {
+            port,
+            host: host.as_deref(),
+            method,
+            target: path,
+            alpn,
+        }),
+    };
+    if let Some(provider) = provider {
+        provider.shutdown()?;
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Environment, Map};
+
+    use super::*;
+    use crate::pkg::conf::Settings;
+
+    #[test]
+    fn flags_win_over_env_and_file() {
+        let file = std::env::temp_dir().join(format!("liteginx-flags-{}.yaml", std::process::id()));
+        std::fs::write(
+            &file,
+            "conf_dir: /file/conf.d\nlog_level: warn\nlog_format: json\nadmin_addr: 127.0.0.1:9001\nupgrade_socket: /file/upgrade.sock\n",
+        )
+        .unwrap();
+        let env = Map::from([
+            ("LOG_LEVEL".to_string(), "debug".to_string()),
+            ("ADMIN_ADDR".to_string(), "127.0.0.1:9002".to_string()),
+        ]);
+        let settings = |args: &[&str]| {
+            let cmd = Cmd::try_parse_from(
+                ["liteginx", "--config", file.to_str().unwrap()]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+            Settings::with_environment(
+                cmd.config.as_deref(),
+                Environment::default().source(Some(env.clone())),
+                cmd.overrides(),
+            )
+            .unwrap()
+        };
+
+        let flagged = settings(&[
+            "listen",
+            "--conf-dir",
+            "/flag/conf.d",
+            "--conf",
+            "a.yaml",
+            "--conf",
+            "b.yaml",
+            "--log-format",
+            "pretty",
+            "--admin-addr",
+            "127.0.0.1:9003",
+        ]);
+        assert_eq!(flagged.liteginx_conf_dir.as_deref(), Some("/flag/conf.d"));
+        assert_eq!(
+            flagged.liteginx_conf_files,
+            Some(vec!["a.yaml".into(), "b.yaml".into()])
+        );
+        assert_eq!(flagged.log_level.as_deref(), Some("debug"));
+        assert_eq!(flagged.log_format.as_deref(), Some("pretty"));
+        assert_eq!(flagged.admin_addr.as_deref(), Some("127.0.0.1:9003"));
+        assert_eq!(
+            flagged.upgrade_socket.as_deref(),
+            Some("/file/upgrade.sock")
+        );
+
+        let unflagged = settings(&["routes", "--port", "5000", "--log-level", "trace"]);
+        assert_eq!(unflagged.liteginx_conf_dir.as_deref(), Some("/file/conf.d"));
+        assert_eq!(unflagged.log_level.as_deref(), Some("trace"));
+        assert_eq!(unflagged.log_format.as_deref(), Some("json"));
+        assert_eq!(unflagged.admin_addr.as_deref(), Some("127.0.0.1:9002"));
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_log_format() {
+        assert!(Cmd::try_parse_from(["liteginx", "listen", "--log-format", "xml"]).is_err());
+        assert!(Cmd::try_parse_from(["liteginx"]).is_err());
+    }
 }
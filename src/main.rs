@@ -1,12 +1,25 @@
+use std::process::ExitCode;
+
 use cmd::run;
+use pkg::server::handoff::take_systemd_fds;
 
 mod cmd;
 mod pkg;
 pub mod prelude;
 
-#[tokio::main]
-async fn main() -> prelude::Result<()> {
-    tracing_subscriber::fmt::init();
-    run().await?;
-    Ok(())
+fn main() -> ExitCode {
+    // taken while this is the only thread, as it clears environment variables
+    let systemd_fds = take_systemd_fds();
+    let r = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(prelude::ProxyError::from)
+        .and_then(|runtime| runtime.block_on(run(systemd_fds)));
+    match r {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", prelude::describe(&e));
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,373 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use serde_json::{json, Map, Value};
+use time::{
+    format_description::{well_known::Rfc3339, FormatItem},
+    macros::format_description,
+    OffsetDateTime,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    pkg::{conf::settings, server::shutdown::Shutdown, telemetry::RequestRecord},
+    prelude::{ProxyError, Result},
+};
+
+const COMBINED: &str = r#"$remote_addr - - [$time_local] "$request" $status $bytes_sent "$http_referer" "$http_user_agent""#;
+
+const TIME_LOCAL: &[FormatItem] = format_description!(
+    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    RemoteAddr,
+    RemotePort,
+    Listener,
+    Host,
+    Method,
+    Path,
+    Protocol,
+    Request,
+    UpstreamUri,
+    Route,
+    UpstreamAddr,
+    Status,
+    BytesSent,
+    BytesReceived,
+    RequestTime,
+    RequestId,
+    Referer,
+    UserAgent,
+    TimeLocal,
+    TimeIso8601,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("remote_addr", Field::RemoteAddr),
+    ("remote_port", Field::RemotePort),
+    ("listener", Field::Listener),
+    ("host", Field::Host),
+    ("request_method", Field::Method),
+    ("request_uri", Field::Path),
+    ("server_protocol", Field::Protocol),
+    ("request", Field::Request),
+    ("upstream_uri", Field::UpstreamUri),
+    ("route", Field::Route),
+    ("upstream_addr", Field::UpstreamAddr),
+    ("status", Field::Status),
+    ("bytes_sent", Field::BytesSent),
+    ("bytes_received", Field::BytesReceived),
+    ("request_time", Field::RequestTime),
+    ("request_id", Field::RequestId),
+    ("http_referer", Field::Referer),
+    ("http_user_agent", Field::UserAgent),
+    ("time_local", Field::TimeLocal),
+    ("time_iso8601", Field::TimeIso8601),
+];
+
+impl Field {
+    fn named(name: &str) -> Option<Self> {
+        FIELDS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, field)| *field)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    Json,
+    Template(Vec<Part>),
+}
+
+impl Format {
+    fn parse(format: &str) -> Result<Self> {
+        match format {
+            "combined" => Self::template(COMBINED),
+            "json" => Ok(Self::Json),
+            template => Self::template(template),
+        }
+    }
+
+    /// Splits a template into literal text and `$variable`s, a `$` not followed
+    /// by a name being kept as is.
+    fn template(template: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('$') {
+            let name_len = rest[start + 1..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len() - start - 1);
+            let name = &rest[start + 1..start + 1 + name_len];
+            let literal = match name {
+                "" => &rest[..start + 1],
+                _ => &rest[..start],
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.to_string()));
+            }
+            if !name.is_empty() {
+                let field = Field::named(name).ok_or_else(|| {
+                    ProxyError::InvalidAccessLogConf(format!("unknown variable ${}", name))
+                })?;
+                parts.push(Part::Field(field));
+            }
+            rest = &rest[start + 1 + name_len..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(Self::Template(parts))
+    }
+
+    fn render(&self, entry: &Entry) -> String {
+        match self {
+            Self::Json => {
+                let fields = FIELDS
+                    .iter()
+                    .filter(|(_, field)| !matches!(field, Field::Request | Field::TimeLocal))
+                    .map(|(name, field)| (name.to_string(), entry.value(*field)))
+                    .collect::<Map<_, _>>();
+                Value::Object(fields).to_string()
+            }
+            Self::Template(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    Part::Literal(literal) => literal.clone(),
+                    Part::Field(field) => match entry.value(*field) {
+                        Value::Null => "-".into(),
+                        Value::String(s) => s,
+                        value => value.to_string(),
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+/// One line of the access log, for an HTTP request or a TCP session.
+struct Entry<'a> {
+    listen: u16,
+    client: SocketAddr,
+    record: &'a RequestRecord,
+    elapsed: Duration,
+    time: OffsetDateTime,
+}
+
+impl Entry<'_> {
+    fn value(&self, field: Field) -> Value {
+        let record = self.record;
+        match field {
+            Field::RemoteAddr => json!(self.client.ip().to_string()),
+            Field::RemotePort => json!(self.client.port()),
+            Field::Listener => json!(self.listen),
+            Field::Host => json!(record.host),
+            Field::Method => json!(record.method),
+            Field::Path => json!(record.path),
+            Field::Protocol => json!(record.protocol),
+            Field::Request => {
+                let request = [&record.method, &record.path, &record.protocol]
+                    .into_iter()
+                    .flatten()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                json!((!request.is_empty()).then_some(request))
+            }
+            Field::UpstreamUri => json!(record.rewritten),
+            Field::Route => json!(record.route),
+            Field::UpstreamAddr => json!(record.target),
+            Field::Status => json!(record.status),
+            Field::BytesSent => json!(record.bytes_sent),
+            Field::BytesReceived => json!(record.bytes_received),
+            Field::RequestTime => json!((self.elapsed.as_secs_f64() * 1000.0).round() / 1000.0),
+            Field::RequestId => json!(record.request_id),
+            Field::Referer => json!(record.referer),
+            Field::UserAgent => json!(record.user_agent),
+            Field::TimeLocal => json!(self.time.format(TIME_LOCAL).ok()),
+            Field::TimeIso8601 => json!(self.time.format(&Rfc3339).ok()),
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File { path: String, file: File },
+}
+
+fn open(path: &str) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+struct AccessLog {
+    format: Format,
+    sink: Mutex<Sink>,
+}
+
+/// Starts writing access logs if `ACCESS_LOG` is set, to stdout or the file it
+/// names, in the format of `ACCESS_LOG_FORMAT`.
+pub fn init() -> Result<()> {
+    let sink = match settings.access_log.as_deref() {
+        None | Some("off") => return Ok(()),
+        Some("stdout") => Sink::Stdout,
+        Some(path) => Sink::File {
+            path: path.to_string(),
+            file: open(path)?,
+        },
+    };
+    let format = Format::parse(settings.access_log_format.as_deref().unwrap_or("combined"))?;
+    let log = AccessLog {
+        format,
+        sink: Mutex::new(sink),
+    };
+    if ACCESS_LOG.set(log).is_err() {
+        tracing::warn!("access log already set up");
+    }
+    Ok(())
+}
+
+/// Logs a finished request or session from `client` on `listen`.
+pub fn log(listen: u16, client: SocketAddr, record: &RequestRecord, elapsed: Duration) {
+    let Some(log) = ACCESS_LOG.get() else {
+        return;
+    };
+    let entry = Entry {
+        listen,
+        client,
+        record,
+        elapsed,
+        time: OffsetDateTime::now_utc(),
+    };
+    let mut line = log.format.render(&entry);
+    line.push('\n');
+    let written = match *log.sink.lock().unwrap_or_else(|e| e.into_inner()) {
+        Sink::Stdout => std::io::stdout().lock().write_all(line.as_bytes()),
+        Sink::File { ref mut file, .. } => file.write_all(line.as_bytes()),
+    };
+    if let Err(e) = written {
+        tracing::warn!("failed writing access log: {:?}", e);
+    }
+}
+
+/// Reopens the access log file on SIGUSR1 until shutdown, for log rotation.
+pub async fn reopen_on_signal(shutdown: Shutdown) -> Result<()> {
+    let Some(log) = ACCESS_LOG.get() else {
+        return Ok(());
+    };
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            _ = sigusr1.recv() => {},
+            _ = shutdown.triggered() => return Ok(()),
+        }
+        if let Sink::File {
+            ref path,
+            ref mut file,
+        } = *log.sink.lock().unwrap_or_else(|e| e.into_inner())
+        {
+            match open(path) {
+                Ok(reopened) => {
+                    *file = reopened;
+                    tracing::info!("reopened access log {}", path);
+                }
+                Err(e) => tracing::error!("failed reopening access log {}: {:?}", path, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(record: &RequestRecord) -> Entry<'_> {
+        Entry {
+            listen: 8080,
+            client: "10.0.0.1:51234".parse().unwrap(),
+            record,
+            elapsed: Duration::from_millis(1520),
+            time: OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+        }
+    }
+
+    fn request() -> RequestRecord {
+        RequestRecord {
+            host: Some("example.com".into()),
+            method: Some("GET".into()),
+            path: Some("/api/users".into()),
+            protocol: Some("HTTP/1.1".into()),
+            user_agent: Some("curl/8.0".into()),
+            rewritten: Some("/users".into()),
+            route: Some("/api".into()),
+            target: Some("localhost:3000".into()),
+            status: Some(200),
+            bytes_received: 78,
+            bytes_sent: 512,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_combined() -> Result<()> {
+        let record = request();
+        assert_eq!(
+            Format::parse("combined")?.render(&entry(&record)),
+            r#"10.0.0.1 - - [14/Nov/2023:22:13:20 +0000] "GET /api/users HTTP/1.1" 200 512 "-" "curl/8.0""#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn renders_json() -> Result<()> {
+        let record = request();
+        let line: Value = serde_json::from_str(&Format::parse("json")?.render(&entry(&record)))?;
+        assert_eq!(line["remote_addr"], "10.0.0.1");
+        assert_eq!(line["upstream_uri"], "/users");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["request_time"], 1.52);
+        assert_eq!(line["request_id"], Value::Null);
+        assert_eq!(line["time_iso8601"], "2023-11-14T22:13:20Z");
+        Ok(())
+    }
+
+    #[test]
+    fn renders_template() -> Result<()> {
+        let record = RequestRecord {
+            protocol: Some("TCP".into()),
+            target: Some("db:5432".into()),
+            bytes_sent: 10,
+            ..Default::default()
+        };
+        let format = Format::parse(
+            "$remote_addr:$remote_port $request $upstream_addr $status $bytes_sent$ $request_time",
+        )?;
+        assert_eq!(
+            format.render(&entry(&record)),
+            "10.0.0.1:51234 TCP db:5432 - 10$ 1.52"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_variable() {
+        assert!(matches!(
+            Format::parse("$remote_addr $nope"),
+            Err(ProxyError::InvalidAccessLogConf(_))
+        ));
+    }
+}
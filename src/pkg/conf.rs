@@ -1,24 +1,125 @@
-use config::{Config, ConfigError, Environment};
+use std::{path::Path, sync::OnceLock};
+
+use config::{Config, ConfigError, Environment, File, Value};
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+
+use super::spec::config::{Http2Conf, PoolConf, TimeoutsConf};
+use crate::prelude::Result;
 
-#[derive(Deserialize)]
+/// Settings also taken by a shorter name, the full one winning if both are set.
+const SHORT_NAMES: [(&str, &str); 2] = [
+    ("conf_dir", "liteginx_conf_dir"),
+    ("conf_files", "liteginx_conf_files"),
+];
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Settings {
-    pub liteginx_conf_dir: String,
+    pub liteginx_conf_dir: Option<String>,
+    #[serde(default, deserialize_with = "list")]
+    pub liteginx_conf_files: Option<Vec<String>>,
     pub not_found_message: Option<String>,
     pub upstream_reconnect_heartbeat: Option<String>,
     pub upstream_reconnect_max_retries: Option<u32>,
+    pub shutdown_drain_timeout: Option<String>,
+    pub upgrade_socket: Option<String>,
+    pub tcp_buffer_size: Option<usize>,
+    pub listen_address: Option<String>,
+    pub max_header_size: Option<usize>,
+    pub max_headers: Option<usize>,
+    pub tls_reload_interval: Option<String>,
+    pub tls_expiry_warning: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    pub otlp_service_name: Option<String>,
+    pub otlp_sampling_ratio: Option<f64>,
+    pub admin_addr: Option<String>,
+    pub admin_timeout: Option<String>,
+    pub access_log: Option<String>,
+    pub access_log_format: Option<String>,
+    pub log_level: Option<String>,
+    pub log_format: Option<String>,
+    pub defaults: Option<Defaults>,
+}
+
+/// A list given as is or, as environment variables have to, comma separated.
+fn list<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        Separated(String),
+        Items(Vec<String>),
+    }
+    Ok(
+        Option::<List>::deserialize(deserializer)?.map(|list| match list {
+            List::Separated(items) => items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect(),
+            List::Items(items) => items,
+        }),
+    )
+}
+
+/// Route settings applied before those of each conf.
+#[derive(Debug, Default, Deserialize)]
+pub struct Defaults {
+    pub timeouts: Option<TimeoutsConf>,
+    pub pool: Option<PoolConf>,
+    pub http2: Option<Http2Conf>,
 }
 
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let conf = Config::builder()
-            .add_source(Environment::default())
-            .build()?;
-        conf.try_deserialize()
+    /// Reads `config`, a YAML, TOML or JSON file, with environment variables
+    /// taking precedence and `overrides`, from command line flags, over both.
+    /// Nested keys take `__` in variable names, as in `DEFAULTS__TIMEOUTS__CONNECT`.
+    pub fn new(
+        config: Option<&Path>,
+        overrides: Vec<(&str, Value)>,
+    ) -> std::result::Result<Self, ConfigError> {
+        Self::with_environment(config, Environment::default(), overrides)
+    }
+
+    /// Like [`Settings::new`], with the variables read from `env`, which tests
+    /// give a fixed source instead of changing the process environment.
+    pub fn with_environment(
+        config: Option<&Path>,
+        env: Environment,
+        overrides: Vec<(&str, Value)>,
+    ) -> std::result::Result<Self, ConfigError> {
+        let mut conf = Config::builder();
+        if let Some(path) = config {
+            conf = conf.add_source(File::from(path));
+        }
+        let read = conf.add_source(env.separator("__")).build()?;
+        let mut conf = Config::builder().add_source(read.clone());
+        for (short, key) in SHORT_NAMES {
+            if let Ok(value) = read.get::<Value>(short) {
+                conf = conf.set_default(key, value)?;
+            }
+        }
+        for (key, value) in overrides {
+            conf = conf.set_override(key, value)?;
+        }
+        conf.build()?.try_deserialize()
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Loads the settings once at startup, before anything reads `settings`.
+pub fn init(config: Option<&Path>, overrides: Vec<(&str, Value)>) -> Result<()> {
+    if SETTINGS.set(Settings::new(config, overrides)?).is_err() {
+        tracing::warn!("settings already loaded");
     }
+    Ok(())
 }
 
 lazy_static! {
-    pub static ref settings: Settings = Settings::new().expect("improperly configured");
+    /// The settings loaded by `init`, all unset if it never ran.
+    pub static ref settings: &'static Settings = SETTINGS.get_or_init(Settings::default);
 }
@@ -0,0 +1,267 @@
+use std::{fmt::Write as _, sync::Arc};
+
+use crate::{
+    pkg::{
+        server::{
+            helpers::{prefixes, rewrite_path},
+            sni::ClientHello,
+        },
+        spec::routes::{ForwardPolicy, Route, UpstreamTarget},
+    },
+    prelude::{ProxyError, Result},
+};
+
+/// A request to explain the routing of, as given to `liteginx routes`.
+pub struct Probe<'a> {
+    pub port: u16,
+    pub host: Option<&'a str>,
+    pub method: &'a str,
+    pub target: &'a str,
+    /// Protocols offered in the ClientHello, for `sni` routes.
+    pub alpn: &'a [String],
+}
+
+/// Lines of `name  value`, with values lined up.
+#[derive(Default)]
+struct Report(Vec<(&'static str, String)>);
+
+impl Report {
+    fn line(&mut self, name: &'static str, value: impl Into<String>) {
+        self.0.push((name, value.into()));
+    }
+
+    fn targets(&mut self, targets: &[UpstreamTarget]) {
+        if targets.is_empty() {
+            self.line("targets", "none");
+        }
+        for target in targets {
+            let mut notes = vec![];
+            if target.http2 {
+                notes.push("http2".to_string());
+            }
+            if target.tls.is_some() {
+                notes.push("tls".to_string());
+            }
+            if let Some(version) = target.proxy_protocol {
+                notes.push(format!("proxy protocol {:?}", version).to_lowercase());
+            }
+            if let Some(ref check) = target.check {
+                notes.push(format!(
+                    "health checked every {}",
+                    humantime::format_duration(check.interval)
+                ));
+            }
+            let mut line = format!("{}:{}", &target.host, &target.port);
+            if !notes.is_empty() {
+                let _ = write!(line, " ({})", notes.join(", "));
+            }
+            self.line("target", line);
+        }
+    }
+
+    fn render(&self) -> String {
+        let width = self.0.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        self.0
+            .iter()
+            .map(|(name, value)| format!("{:width$}  {}\n", name, value, width = width))
+            .collect()
+    }
+}
+
+/// Describes how `probe` would be routed by `routes`: the endpoint it matches
+/// and the prefixes tried to find it, what is sent upstream and to which
+/// targets. Nothing is connected to.
+pub fn explain(routes: &[Arc<Route>], probe: &Probe) -> Result<String> {
+    let route = routes
+        .iter()
+        .find(|route| route.listen == probe.port)
+        .ok_or(ProxyError::NoRouteOnPort(probe.port))?;
+    let mut report = Report::default();
+    let tls = match route.tls {
+        Some(_) => ", tls",
+        None => "",
+    };
+    if let Some(ref policy) = route.forward {
+        report.line("listener", format!("{} (forward{})", route.listen, tls));
+        forward(&mut report, policy, probe);
+    } else if route.endpoints.is_some() {
+        report.line("listener", format!("{} (http{})", route.listen, tls));
+        http(&mut report, route, probe);
+    } else {
+        report.line("listener", format!("{} (tcp{})", route.listen, tls));
+        tcp(&mut report, route, probe);
+    }
+    Ok(report.render())
+}
+
+fn http(report: &mut Report, route: &Route, probe: &Probe) {
+    report.line(
+        "request",
+        format!("{} {} HTTP/1.1", probe.method, probe.target),
+    );
+    if let Some(host) = probe.host {
+        report.line("host", host);
+    }
+    let path = probe.target.split('?').next().unwrap_or_default();
+    let Some(router) = route.endpoints.as_ref() else {
+        return;
+    };
+    let mut matched = None;
+    for prefix in prefixes(path) {
+        match router.at(&prefix) {
+            Ok(m) => {
+                report.line("tried", format!("{} (matched)", &prefix));
+                matched = Some(m.value);
+                break;
+            }
+            Err(_) => report.line("tried", prefix),
+        }
+    }
+    let Some(endpoint) = matched else {
+        let known = route
+            .paths
+            .iter()
+            .map(|endpoint| endpoint.path.as_str())
+            .collect::<Vec<_>>();
+        report.line("endpoint", "none, answered with 404");
+        report.line("endpoints", known.join(", "));
+        return;
+    };
+    report.line("endpoint", &endpoint.path);
+    if !endpoint.clients.is_empty() {
+        report.line(
+            "clients",
+            format!(
+                "{} (client certificate required)",
+                endpoint.clients.join(", ")
+            ),
+        );
+    }
+    report.line(
+        "rewrite",
+        endpoint.rewrite.as_deref().unwrap_or("none, sent as is"),
+    );
+    report.line(
+        "upstream",
+        format!(
+            "{} {} HTTP/1.1",
+            probe.method,
+            rewrite_path(endpoint, probe.target)
+        ),
+    );
+    report.targets(&route.targets);
+}
+
+fn tcp(report: &mut Report, route: &Route, probe: &Probe) {
+    if route.sni.is_empty() {
+        report.targets(&route.targets);
+        return;
+    }
+    let hello = ClientHello {
+        server_name: probe.host.map(String::from),
+        alpn: probe.alpn.to_vec(),
+    };
+    report.line("sni", probe.host.unwrap_or("none"));
+    for sni in &route.sni {
+        let mut line = sni
+            .names
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !sni.alpn.is_empty() {
+            let _ = write!(line, " with alpn {}", sni.alpn.join(", "));
+        }
+        if sni.matches(&hello) {
+            report.line("tried", line + " (matched)");
+            report.targets(&sni.targets);
+            return;
+        }
+        report.line("tried", line);
+    }
+    report.line("sni route", "none, using the route's own targets");
+    report.targets(&route.targets);
+}
+
+fn forward(report: &mut Report, policy: &ForwardPolicy, probe: &Probe) {
+    let connect = probe.method.eq_ignore_ascii_case("CONNECT");
+    let destination = probe.host.unwrap_or(probe.target);
+    let (host, port) = match destination.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => (host, port.parse().unwrap_or(80)),
+        _ if connect => (destination, 443),
+        _ => (destination, 80),
+    };
+    report.line("destination", format!("{}:{}", host, port));
+    if policy.permits(host, port) {
+        report.line("policy", "allowed");
+        let how = match connect {
+            true => "tunnelled",
+            false => "relayed",
+        };
+        report.line("target", format!("{}:{} ({})", host, port, how));
+    } else {
+        report.line("policy", "denied, answered with 403");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::spec::config::IngressConf;
+
+    fn routes() -> Result<Vec<Arc<Route>>> {
+        Route::new(&IngressConf::load(&["fixtures"])?)
+    }
+
+    /// Whether `report` has a `name` line reading `value`.
+    fn has(report: &str, name: &str, value: &str) -> bool {
+        report.lines().any(|line| {
+            line.strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with("  ") && rest.trim_start() == value)
+        })
+    }
+
+    fn probe(port: u16, host: Option<&'static str>, target: &'static str) -> Probe<'static> {
+        Probe {
+            port,
+            host,
+            method: "GET",
+            target,
+            alpn: &[],
+        }
+    }
+
+    #[test]
+    fn explains_http() -> Result<()> {
+        let report = explain(&routes()?, &probe(5000, None, "/two/users?page=2"))?;
+        assert!(has(&report, "tried", "/two/users"));
+        assert!(has(&report, "tried", "/two (matched)"));
+        assert!(has(&report, "endpoint", "/two"));
+        assert!(has(&report, "upstream", "GET / HTTP/1.1"));
+        assert!(has(&report, "target", "localhost:3000"));
+
+        let report = explain(&routes()?, &probe(5000, None, "/three"))?;
+        assert!(has(&report, "endpoint", "none, answered with 404"));
+        Ok(())
+    }
+
+    #[test]
+    fn explains_sni_and_forward() -> Result<()> {
+        let report = explain(&routes()?, &probe(5004, Some("www.app.example.com"), "/"))?;
+        assert!(report.contains("app.example.com, *.app.example.com (matched)"));
+        assert!(has(&report, "target", "localhost:4443"));
+
+        let report = explain(&routes()?, &probe(5003, Some("admin.example.com:443"), "/"))?;
+        assert!(has(&report, "policy", "denied, answered with 403"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_port() -> Result<()> {
+        assert!(matches!(
+            explain(&routes()?, &probe(1, None, "/")),
+            Err(ProxyError::NoRouteOnPort(1))
+        ));
+        Ok(())
+    }
+}
@@ -1,29 +1,191 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, io::Write, os::fd::RawFd, sync::Arc, time::Duration};
 
 use crate::prelude::{ProxyError, Result};
-use server::downstream::ListenDownstream;
+use conf::settings;
+use humantime::parse_duration;
+use server::{
+    acme,
+    admin::{self, Loaded, Reload, Runtime},
+    downstream::ListenDownstream,
+    handoff::Listeners,
+    health,
+    metrics::METRICS,
+    shutdown::{shutdown_signal, Shutdown},
+    tls,
+};
 use spec::{config::IngressConf, routes::Route};
-use tokio::task::JoinSet;
+use tokio::{sync::mpsc, task::JoinSet};
 
+pub mod access_log;
 pub mod conf;
+pub mod explain;
 pub mod server;
 pub mod spec;
+pub mod telemetry;
 
-pub async fn listen() -> Result<()> {
-    let configs = IngressConf::new()?;
-    let routes = Route::new(configs)?;
-    let set = routes.iter().fold(JoinSet::new(), |mut set, route| {
-        let route = Arc::clone(&route);
-        set.spawn(async move {
-            route.serve().await?;
-            Ok::<(), ProxyError>(())
+/// Routes loaded from one read of the confs, along with their listeners and
+/// background tasks, served until shutdown or until a reload replaces them.
+struct Generation {
+    shutdown: Shutdown,
+    set: JoinSet<Result<()>>,
+}
+
+impl Generation {
+    fn load() -> Result<Loaded> {
+        let confs = IngressConf::new()?;
+        let routes = Route::new(&confs)?;
+        Ok(Loaded { confs, routes })
+    }
+
+    fn start(routes: &[Arc<Route>], listeners: &Arc<Listeners>) -> Result<Self> {
+        listeners.release_unused(&routes.iter().map(|r| r.listen).collect::<Vec<_>>());
+        let shutdown = Shutdown::default();
+        let mut set = routes.iter().fold(JoinSet::new(), |mut set, route| {
+            route
+                .all_targets()
+                .for_each(|target| METRICS.observe_state(target));
+            let checks = health::watch(Arc::clone(route), shutdown.clone());
+            set.spawn(async move {
+                checks.await;
+                Ok::<(), ProxyError>(())
+            });
+            let route = Arc::clone(route);
+            let listeners = Arc::clone(listeners);
+            let shutdown = shutdown.clone();
+            set.spawn(async move {
+                route.serve(listeners, shutdown).await?;
+                Ok::<(), ProxyError>(())
+            });
+            set
+        });
+        // listeners sharing a certificate are kept up to date together
+        let tls_listeners = routes.iter().filter_map(|route| route.tls.clone()).fold(
+            BTreeMap::<_, Vec<_>>::new(),
+            |mut listeners, tls| {
+                listeners.entry(tls.cert.clone()).or_default().push(tls);
+                listeners
+            },
+        );
+        let reload_interval =
+            parse_duration(&settings.tls_reload_interval.clone().unwrap_or("10s".into()))?;
+        let expiry_warning =
+            parse_duration(&settings.tls_expiry_warning.clone().unwrap_or("14d".into()))?;
+        for tlss in tls_listeners.into_values() {
+            if tlss[0].acme.is_some() {
+                let tlss = tlss.clone();
+                let shutdown = shutdown.clone();
+                set.spawn(async move {
+                    acme::manage(tlss, shutdown).await;
+                    Ok::<(), ProxyError>(())
+                });
+            }
+            let shutdown = shutdown.clone();
+            set.spawn(async move {
+                tls::watch(tlss, reload_interval, expiry_warning, shutdown).await;
+                Ok::<(), ProxyError>(())
+            });
+        }
+        tracing::debug!("spawning set: {:?}", &set);
+        Ok(Self { shutdown, set })
+    }
+
+    /// Stops accepting, waits up to `drain_timeout` for open connections and
+    /// closes the rest.
+    async fn drain(self, drain_timeout: Duration) {
+        let (drained, closed) = self.shutdown.drain(drain_timeout).await;
+        self.set.join_all().await;
+        tracing::info!(
+            "drained {} connections, force closed {} remaining",
+            drained,
+            closed
+        );
+    }
+}
+
+enum Event {
+    Stopped(Result<()>),
+    Reload(Reload),
+}
+
+/// Loads the confs and prints the routes they make, without listening.
+pub fn dry_run() -> Result<()> {
+    let loaded = Generation::load()?;
+    let mut routes = serde_json::to_string_pretty(&admin::routes(&loaded.routes))?;
+    routes.push('\n');
+    Ok(std::io::stdout().write_all(routes.as_bytes())?)
+}
+
+/// Loads the confs and prints how `probe` would be routed, without listening.
+pub fn explain_route(probe: &explain::Probe) -> Result<()> {
+    let loaded = Generation::load()?;
+    let report = explain::explain(&loaded.routes, probe)?;
+    Ok(std::io::stdout().write_all(report.as_bytes())?)
+}
+
+/// Serves the routes loaded from the confs until shutdown, on the
+/// `systemd_fds` sockets systemd passed and those handed over on `upgrade`.
+pub async fn listen(upgrade: bool, systemd_fds: RawFd) -> Result<()> {
+    let loaded = Generation::load()?;
+    access_log::init()?;
+    let socket = settings.upgrade_socket.as_deref();
+    let drain_timeout = parse_duration(
+        &settings
+            .shutdown_drain_timeout
+            .clone()
+            .unwrap_or("30s".into()),
+    )?;
+    let listeners = Arc::new(Listeners::inherit(systemd_fds, upgrade, socket).await?);
+    let mut generation = Generation::start(&loaded.routes, &listeners)?;
+    let (reloads, mut reload_requests) = mpsc::channel(1);
+    let runtime = Arc::new(Runtime::new(loaded, reloads));
+    // stops what outlives reloads, once the last generation drained
+    let stopping = Shutdown::default();
+    tokio::spawn(access_log::reopen_on_signal(stopping.clone()));
+    if let Some(addr) = settings.admin_addr.clone() {
+        let runtime = Arc::clone(&runtime);
+        let stopping = stopping.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(&addr, runtime, stopping).await {
+                tracing::error!("admin listener on {} failed: {:?}", &addr, e);
+            }
         });
-        set
-    });
-    tracing::debug!("spawning set: {:?}", &set);
-    tokio::select! {
-        _ = set.join_all() => {},
-        _ = tokio::signal::ctrl_c() => {}
+    }
+    let stop = shutdown_signal();
+    // upgrades are only served on a socket set up for them
+    let handoff = async {
+        let Some(socket) = socket else {
+            return std::future::pending::<()>().await;
+        };
+        if let Err(e) = listeners.serve_handoff(socket).await {
+            tracing::warn!("listener handoff unavailable: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::pin!(stop, handoff);
+    let r = loop {
+        let event = tokio::select! {
+            _ = async { while generation.set.join_next().await.is_some() {} } => Event::Stopped(Ok(())),
+            r = &mut stop => Event::Stopped(r),
+            _ = &mut handoff => Event::Stopped(Ok(())),
+            Some(reload) = reload_requests.recv() => Event::Reload(reload),
+        };
+        match event {
+            Event::Stopped(r) => break r,
+            Event::Reload(reload) => {
+                let reloaded = Generation::load().and_then(|loaded| {
+                    runtime.keep_rotation(&loaded);
+                    listeners.recycle();
+                    let next = Generation::start(&loaded.routes, &listeners)?;
+                    tokio::spawn(std::mem::replace(&mut generation, next).drain(drain_timeout));
+                    runtime.set_loaded(loaded);
+                    tracing::info!("reloaded confs");
+                    Ok(())
+                });
+                let _ = reload.send(reloaded);
+            }
+        }
     };
-    Ok(())
+    generation.drain(drain_timeout).await;
+    stopping.trigger();
+    r
 }
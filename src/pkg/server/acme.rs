@@ -0,0 +1,779 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use humantime::parse_duration;
+use lazy_static::lazy_static;
+use rcgen::{
+    CertificateParams, CustomExtension, DistinguishedName, DnType, KeyPair, PKCS_ECDSA_P256_SHA256,
+};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+
+use crate::{
+    pkg::{
+        server::{
+            http::{BodyReader, Header, HttpConn, RequestHead},
+            shutdown::Shutdown,
+            tls::{client_config, handshake, not_after, Tls},
+        },
+        spec::config::{AcmeChallenge, AcmeConf},
+    },
+    prelude::{ProxyError, Result},
+};
+
+const DEFAULT_STATE_DIR: &str = "/var/lib/liteginx/acme";
+const DEFAULT_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+const HTTP01_PREFIX: &str = "/.well-known/acme-challenge/";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often pending authorizations and orders are polled, and for how long.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+/// How long to wait before trying again after failing to get a certificate.
+const RETRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Upper bound on sleeping between renewal checks.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+lazy_static! {
+    /// Key authorizations for pending HTTP-01 challenges, by token.
+    static ref HTTP_CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    /// Certificates for pending TLS-ALPN-01 challenges, by domain.
+    static ref TLS_ALPN_CHALLENGES: Mutex<HashMap<String, Arc<CertifiedKey>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn acme_error(e: impl std::fmt::Display) -> ProxyError {
+    ProxyError::AcmeError(e.to_string())
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// The response to an HTTP-01 validation request for `path`, if it is one for a
+/// pending challenge.
+pub fn http01_response(path: &str) -> Option<String> {
+    let token = path.strip_prefix(HTTP01_PREFIX)?;
+    let challenges = HTTP_CHALLENGES.lock().unwrap_or_else(|e| e.into_inner());
+    let key_authorization = challenges.get(token)?;
+    tracing::info!("answering acme http-01 challenge {}", token);
+    Some(format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/octet-stream\r\n\
+        Content-Length: {}\r\n\
+        \r\n\
+        {}",
+        key_authorization.len(),
+        key_authorization
+    ))
+}
+
+/// The certificate answering a TLS-ALPN-01 validation handshake for `domain`.
+pub fn challenge_cert(domain: &str) -> Option<Arc<CertifiedKey>> {
+    let challenges = TLS_ALPN_CHALLENGES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let cert = challenges.get(&domain.to_ascii_lowercase()).cloned();
+    if cert.is_some() {
+        tracing::info!("answering acme tls-alpn-01 challenge for {}", domain);
+    }
+    cert
+}
+
+/// A challenge being answered, withdrawn once it is dropped.
+enum Pending {
+    Http01(String),
+    TlsAlpn01(String),
+}
+
+impl Pending {
+    fn new(
+        kind: AcmeChallenge,
+        domain: &str,
+        token: &str,
+        key_authorization: String,
+    ) -> Result<Self> {
+        match kind {
+            AcmeChallenge::Http01 => {
+                HTTP_CHALLENGES
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(token.to_string(), key_authorization);
+                Ok(Pending::Http01(token.to_string()))
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let digest = digest(&SHA256, key_authorization.as_bytes());
+                let mut params =
+                    CertificateParams::new(vec![domain.to_string()]).map_err(acme_error)?;
+                params.custom_extensions =
+                    vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+                let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).map_err(acme_error)?;
+                let cert = params.self_signed(&key).map_err(acme_error)?;
+                let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+                // webpki rejects the critical acmeIdentifier extension, so the
+                // pair isn't checked the way `certified_key` does
+                let certified =
+                    CertifiedKey::new(vec![cert.der().clone()], any_supported_type(&key_der)?);
+                TLS_ALPN_CHALLENGES
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(domain.to_ascii_lowercase(), Arc::new(certified));
+                Ok(Pending::TlsAlpn01(domain.to_ascii_lowercase()))
+            }
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        match self {
+            Pending::Http01(token) => {
+                HTTP_CHALLENGES
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(token);
+            }
+            Pending::TlsAlpn01(domain) => {
+                TLS_ALPN_CHALLENGES
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(domain);
+            }
+        }
+    }
+}
+
+impl AcmeChallenge {
+    fn name(&self) -> &'static str {
+        match self {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+impl AcmeConf {
+    pub fn state_dir(&self) -> &str {
+        self.state_dir.as_deref().unwrap_or(DEFAULT_STATE_DIR)
+    }
+
+    /// Where the certificate and key for the ingress conf `name` are kept.
+    pub fn cert_paths(&self, name: &str) -> (String, String) {
+        let dir = Path::new(self.state_dir()).join(name);
+        (
+            dir.join("cert.pem").to_string_lossy().into_owned(),
+            dir.join("key.pem").to_string_lossy().into_owned(),
+        )
+    }
+
+    pub fn renew_before(&self) -> Result<Duration> {
+        match self.renew_before {
+            Some(ref renew_before) => Ok(parse_duration(renew_before)?),
+            None => Ok(DEFAULT_RENEW_BEFORE),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+
+    fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// The ES256 account key requests to the ACME server are signed with.
+struct AccountKey {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl AccountKey {
+    /// Loads the account key kept in `path`, generating and saving one first if
+    /// there is none.
+    fn load(path: &Path) -> Result<Self> {
+        let key = match fs::read_to_string(path) {
+            Ok(pem) => KeyPair::from_pem(&pem).map_err(acme_error)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).map_err(acme_error)?;
+                write_private(path, &key.serialize_pem())?;
+                tracing::info!("created acme account key {}", path.display());
+                key
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &rng)
+                .map_err(acme_error)?;
+        Ok(Self { key, rng })
+    }
+
+    fn jwk(&self) -> Value {
+        // an uncompressed point: 0x04, then x and y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&point[1..33]),
+            "y": b64(&point[33..65]),
+        })
+    }
+
+    /// The RFC 7638 thumbprint of the account key, its members in lexicographic order.
+    fn thumbprint(&self) -> Result<String> {
+        let jwk = serde_json::to_string(&self.jwk())?;
+        Ok(b64(digest(&SHA256, jwk.as_bytes())))
+    }
+
+    /// A flattened JWS of `payload`, or of an empty payload for POST-as-GET.
+    fn sign(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: Option<&Value>,
+    ) -> Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = b64(serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(payload) => b64(serde_json::to_vec(payload)?),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(acme_error)?;
+        Ok(serde_json::to_vec(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature),
+        }))?)
+    }
+}
+
+/// Writes `contents` next to `path`, with `mode` permissions, for renaming over
+/// it once complete. Returns the path written.
+fn stage(path: &Path, contents: &[u8], mode: u32) -> Result<PathBuf> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".tmp");
+    let staged = PathBuf::from(staged);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&staged)?;
+    std::io::Write::write_all(&mut file, contents)?;
+    file.sync_all()?;
+    Ok(staged)
+}
+
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let staged = stage(path, contents.as_bytes(), 0o600)?;
+    Ok(fs::rename(staged, path)?)
+}
+
+/// An ACME account session against one directory.
+struct Client {
+    ca: Option<String>,
+    directory: Directory,
+    key: AccountKey,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    /// Fetches the directory and registers the account, or looks it up if the
+    /// key is already registered.
+    async fn new(conf: &AcmeConf) -> Result<Self> {
+        let key = AccountKey::load(&Path::new(conf.state_dir()).join("account.pem"))?;
+        let response = request(conf.ca.as_deref(), "GET", &conf.directory, None).await?;
+        let mut client = Self {
+            ca: conf.ca.clone(),
+            directory: response.json()?,
+            key,
+            kid: None,
+            nonce: None,
+        };
+        let new_account = client.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": conf.contact });
+        let response = client.post(&new_account, Some(payload)).await?;
+        client.kid = Some(
+            response
+                .header("location")
+                .ok_or(acme_error("account without location"))?
+                .to_string(),
+        );
+        Ok(client)
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = request(self.ca.as_deref(), "HEAD", &self.directory.new_nonce, None).await?;
+        response
+            .header("replay-nonce")
+            .map(String::from)
+            .ok_or(acme_error("no nonce from acme server"))
+    }
+
+    /// Sends a signed request, trying once more with a fresh nonce if the
+    /// server rejected the one used.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<Response> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self
+                .key
+                .sign(url, &nonce, self.kid.as_deref(), payload.as_ref())?;
+            let response = request(self.ca.as_deref(), "POST", url, Some(body)).await?;
+            self.nonce = response.header("replay-nonce").map(String::from);
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem: Problem = response.json().unwrap_or(Problem {
+                kind: String::new(),
+                detail: String::from_utf8_lossy(&response.body).into_owned(),
+            });
+            if problem.kind.ends_with(":badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(acme_error(format!(
+                "{} from {}: {} {}",
+                response.status, url, problem.kind, problem.detail
+            )));
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&mut self, url: &str) -> Result<T> {
+        self.post(url, None).await?.json()
+    }
+
+    /// Answers the challenge of `kind` for one authorization and waits for the
+    /// server to validate it.
+    async fn authorize(&mut self, url: &str, kind: AcmeChallenge) -> Result<()> {
+        let authorization: Authorization = self.get(url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind.name())
+            .ok_or(acme_error(format!(
+                "no {} challenge for {}",
+                kind.name(),
+                &domain
+            )))?;
+        let key_authorization = format!("{}.{}", &challenge.token, self.key.thumbprint()?);
+        let _pending = Pending::new(kind, &domain, &challenge.token, key_authorization)?;
+        tracing::info!("answering {} challenge for {}", kind.name(), &domain);
+        self.post(&challenge.url, Some(json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            sleep(POLL_INTERVAL).await;
+            let authorization: Authorization = self.get(url).await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => {}
+                status => {
+                    return Err(acme_error(format!(
+                        "authorization for {} is {}",
+                        &domain, status
+                    )))
+                }
+            }
+        }
+        Err(acme_error(format!(
+            "authorization for {} timed out",
+            &domain
+        )))
+    }
+
+    /// Polls `url` until the order is in a state other than `waiting_on`.
+    async fn poll_order(&mut self, url: &str, waiting_on: &[&str]) -> Result<Order> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.get(url).await?;
+            if !waiting_on.contains(&order.status.as_str()) {
+                return Ok(order);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        Err(acme_error("order timed out"))
+    }
+}
+
+/// Sends one request to the ACME server over a fresh HTTPS connection.
+async fn request(
+    ca: Option<&str>,
+    method: &str,
+    url: &str,
+    body: Option<Vec<u8>>,
+) -> Result<Response> {
+    let uri: http::Uri = url.parse().map_err(acme_error)?;
+    if uri.scheme() != Some(&http::uri::Scheme::HTTPS) {
+        return Err(acme_error(format!("acme urls need https: {}", url)));
+    }
+    let host = uri
+        .host()
+        .ok_or(acme_error(format!("no host in {}", url)))?;
+    let port = uri.port_u16().unwrap_or(443);
+    let stream = timeout(REQUEST_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| acme_error(format!("timed out connecting to {}", url)))??;
+    let stream = handshake(client_config(ca, &[b"http/1.1"])?, host, stream).await?;
+    let mut conn = HttpConn::new(stream, REQUEST_TIMEOUT);
+
+    let mut head = RequestHead {
+        method: method.to_string(),
+        target: uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        version: 1,
+        headers: Vec::new(),
+    };
+    head.set_header("Host", uri.authority().map_or(host, |a| a.as_str()));
+    head.set_header(
+        "User-Agent",
+        concat!("liteginx/", env!("CARGO_PKG_VERSION")),
+    );
+    head.set_header("Connection", "close");
+    let body = body.unwrap_or_default();
+    if method == "POST" {
+        head.set_header("Content-Type", "application/jose+json");
+        head.set_header("Content-Length", &body.len().to_string());
+    }
+    let mut out = head.encode();
+    out.extend_from_slice(&body);
+    conn.stream.write_all(&out).await?;
+    conn.stream.flush().await?;
+
+    let response = conn.read_response(Instant::now() + REQUEST_TIMEOUT).await?;
+    let mut reader = BodyReader::new(response.framing(method)?);
+    let mut body = Vec::new();
+    while let Some(data) = conn.read_data(&mut reader).await? {
+        body.extend_from_slice(&data);
+    }
+    tracing::debug!("acme {} {}: {}", method, url, response.status);
+    Ok(Response {
+        status: response.status,
+        headers: response.headers,
+        body,
+    })
+}
+
+/// Orders a certificate for the domains in `conf`, saves it along with its key
+/// and presents it on every listener in `listeners`.
+async fn obtain(conf: &AcmeConf, listeners: &[Tls]) -> Result<()> {
+    let mut client = Client::new(conf).await?;
+    let identifiers: Vec<Value> = conf
+        .domains
+        .iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect();
+    let new_order = client.directory.new_order.clone();
+    let response = client
+        .post(&new_order, Some(json!({ "identifiers": identifiers })))
+        .await?;
+    let order_url = response
+        .header("location")
+        .ok_or(acme_error("order without location"))?
+        .to_string();
+    let order: Order = response.json()?;
+    for authorization in &order.authorizations {
+        client.authorize(authorization, conf.challenge).await?;
+    }
+
+    let order = client.poll_order(&order_url, &["pending"]).await?;
+    if order.status != "ready" {
+        return Err(acme_error(format!("order is {}", order.status)));
+    }
+    let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).map_err(acme_error)?;
+    let mut params = CertificateParams::new(conf.domains.clone()).map_err(acme_error)?;
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, &conf.domains[0]);
+    let csr = params.serialize_request(&key).map_err(acme_error)?;
+    client
+        .post(&order.finalize, Some(json!({ "csr": b64(csr.der()) })))
+        .await?;
+    let order = client
+        .poll_order(&order_url, &["ready", "processing"])
+        .await?;
+    let certificate = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(certificate)) => certificate,
+        (status, _) => return Err(acme_error(format!("order is {}", status))),
+    };
+    let chain = client.post(&certificate, None).await?.body;
+
+    // both are written in full before either replaces the pair in use, which
+    // is only ever half replaced if interrupted between the renames
+    let tls = &listeners[0];
+    let staged_key = stage(Path::new(&tls.key), key.serialize_pem().as_bytes(), 0o600)?;
+    let staged_cert = stage(Path::new(&tls.cert), &chain, 0o644)?;
+    fs::rename(staged_key, &tls.key)?;
+    fs::rename(staged_cert, &tls.cert)?;
+    for tls in listeners {
+        tls.reload()?;
+    }
+    Ok(())
+}
+
+/// How long until the certificate of `tls` is due for renewal, or zero if there
+/// is none or it doesn't go with the key, as when a renewal was interrupted.
+/// A `renew_before` reaching back past what `SystemTime` holds is due now too.
+fn renewal_due(tls: &Tls, renew_before: Duration) -> Duration {
+    tls.read()
+        .and_then(|certified| not_after(&certified.cert))
+        .ok()
+        .and_then(|not_after| not_after.checked_sub(renew_before))
+        .and_then(|due| due.duration_since(SystemTime::now()).ok())
+        .unwrap_or_default()
+}
+
+/// Keeps the ACME certificate presented by `listeners` issued and renewed
+/// until shutdown. The listeners all belong to one conf and share its state.
+pub async fn manage(listeners: Vec<Tls>, shutdown: Shutdown) {
+    let Some(conf) = listeners.first().and_then(|tls| tls.acme.clone()) else {
+        return;
+    };
+    let renew_before = conf.renew_before().unwrap_or(DEFAULT_RENEW_BEFORE);
+    loop {
+        let due = renewal_due(&listeners[0], renew_before);
+        let wait = match due.is_zero() {
+            false => due.min(CHECK_INTERVAL),
+            true => match obtain(&conf, &listeners).await {
+                Ok(()) => {
+                    tracing::info!("obtained acme certificate for {:?}", &conf.domains);
+                    renewal_due(&listeners[0], renew_before).clamp(RETRY_INTERVAL, CHECK_INTERVAL)
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "failed to obtain acme certificate for {:?}: {:?}",
+                        &conf.domains,
+                        e
+                    );
+                    RETRY_INTERVAL
+                }
+            },
+        };
+        tokio::select! {
+            _ = sleep(wait) => {}
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    use super::*;
+
+    fn account_key() -> AccountKey {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &rng)
+                .unwrap();
+        AccountKey { key, rng }
+    }
+
+    #[test]
+    fn signs_verifiable_jws() -> Result<()> {
+        let key = account_key();
+        let payload = json!({ "identifiers": [] });
+        let jws: Value = serde_json::from_slice(&key.sign(
+            "https://acme/new-order",
+            "nonce",
+            Some("https://acme/acct/1"),
+            Some(&payload),
+        )?)?;
+        let protected: Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(jws["protected"].as_str().unwrap())
+                .unwrap(),
+        )?;
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["kid"], "https://acme/acct/1");
+        assert!(protected.get("jwk").is_none());
+        let signature = URL_SAFE_NO_PAD
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(signature.len(), 64);
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key.key.public_key().as_ref())
+            .verify(signed.as_bytes(), &signature)
+            .expect("signature does not verify");
+
+        // POST-as-GET signs an empty payload
+        let jws: Value =
+            serde_json::from_slice(&key.sign("https://acme/order/1", "n", None, None)?)?;
+        assert_eq!(jws["payload"], "");
+        Ok(())
+    }
+
+    #[test]
+    fn thumbprint_uses_ordered_members() -> Result<()> {
+        let key = account_key();
+        let jwk = serde_json::to_string(&key.jwk())?;
+        assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+        assert_eq!(key.thumbprint()?.len(), 43);
+        Ok(())
+    }
+
+    #[test]
+    fn renews_pair_left_half_replaced() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("liteginx-acme-{}", std::process::id()));
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let issue = || {
+            let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+            let cert = CertificateParams::new(vec!["a.test".into()])
+                .and_then(|params| params.self_signed(&key))
+                .unwrap();
+            (cert.pem(), key.serialize_pem())
+        };
+        let (issued, issued_key) = issue();
+        write_private(&cert, &issued)?;
+        write_private(&key, &issued_key)?;
+        let tls = Tls::load(cert.to_str().unwrap(), key.to_str().unwrap(), None, &[])?;
+        assert!(!renewal_due(&tls, DEFAULT_RENEW_BEFORE).is_zero());
+        assert!(renewal_due(&tls, Duration::MAX).is_zero());
+        assert!(!dir.join("key.pem.tmp").exists());
+
+        // the key of a renewal got in, its certificate didn't
+        write_private(&key, &issue().1)?;
+        let due = renewal_due(&tls, DEFAULT_RENEW_BEFORE);
+        fs::remove_dir_all(&dir)?;
+        assert!(due.is_zero());
+        Ok(())
+    }
+
+    #[test]
+    fn answers_pending_http01_challenge() -> Result<()> {
+        let path = format!("{}tok-123", HTTP01_PREFIX);
+        assert!(http01_response(&path).is_none());
+        let pending = Pending::new(
+            AcmeChallenge::Http01,
+            "a.test",
+            "tok-123",
+            "tok-123.x".into(),
+        )?;
+        let response = http01_response(&path).expect("missing challenge response");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ntok-123.x"));
+        drop(pending);
+        assert!(http01_response(&path).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn issues_tls_alpn01_challenge_cert() -> Result<()> {
+        let pending = Pending::new(AcmeChallenge::TlsAlpn01, "B.test", "tok", "tok.x".into())?;
+        let cert = challenge_cert("b.test").expect("missing challenge cert");
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert.cert[0]).unwrap();
+        // id-pe-acmeIdentifier, critical, holding the digest of the key authorization
+        let extension = parsed
+            .extensions()
+            .iter()
+            .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .expect("missing acmeIdentifier extension");
+        assert!(extension.critical);
+        assert!(extension
+            .value
+            .ends_with(digest(&SHA256, b"tok.x").as_ref()));
+        drop(pending);
+        assert!(challenge_cert("b.test").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use http::StatusCode;
+use humantime::{format_duration, format_rfc3339_seconds, parse_duration};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use crate::{
+    pkg::{
+        conf::settings,
+        server::{helpers::json_detail, http::HttpConn, metrics::METRICS, shutdown::Shutdown},
+        spec::{
+            config::IngressConf,
+            routes::{HostRule, Route, TargetState, UpstreamTarget},
+        },
+    },
+    prelude::{describe, ProxyError, Result},
+};
+
+/// A request to load the confs again, answered once the new routes are served.
+pub type Reload = oneshot::Sender<Result<()>>;
+
+/// The confs and routes being served.
+pub struct Loaded {
+    pub confs: Vec<IngressConf>,
+    pub routes: Vec<Arc<Route>>,
+}
+
+/// What the admin API reports on and acts upon, shared with `listen`.
+pub struct Runtime {
+    loaded: RwLock<Arc<Loaded>>,
+    reloads: mpsc::Sender<Reload>,
+    started: SystemTime,
+}
+
+impl Runtime {
+    pub fn new(loaded: Loaded, reloads: mpsc::Sender<Reload>) -> Self {
+        Self {
+            loaded: RwLock::new(Arc::new(loaded)),
+            reloads,
+            started: SystemTime::now(),
+        }
+    }
+
+    pub fn loaded(&self) -> Arc<Loaded> {
+        Arc::clone(&self.loaded.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn set_loaded(&self, loaded: Loaded) {
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(loaded);
+    }
+
+    /// Keeps targets of `loaded` that were drained or disabled in the routes
+    /// being served that way, so a reload doesn't put them back in rotation.
+    pub fn keep_rotation(&self, loaded: &Loaded) {
+        let current = self.loaded();
+        let states = current
+            .routes
+            .iter()
+            .flat_map(|route| route.all_targets())
+            .map(|target| (address(target), target.rotation.get()))
+            .filter(|(_, state)| *state != TargetState::Active)
+            .collect::<HashMap<_, _>>();
+        for target in loaded.routes.iter().flat_map(|route| route.all_targets()) {
+            if let Some(state) = states.get(&address(target)) {
+                target.rotation.set(*state);
+            }
+        }
+    }
+
+    async fn reload(&self) -> Result<()> {
+        let (reply, done) = oneshot::channel();
+        self.reloads
+            .send(reply)
+            .await
+            .map_err(|_| ProxyError::ShuttingDown)?;
+        done.await.map_err(|_| ProxyError::ShuttingDown)?
+    }
+}
+
+/// Answers admin requests on `addr` until shutdown, one request per connection.
+/// A bare port is bound on localhost.
+pub async fn serve(addr: &str, runtime: Arc<Runtime>, shutdown: Shutdown) -> Result<()> {
+    let addr = match addr.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr.to_string(),
+    };
+    let admin_timeout = parse_duration(&settings.admin_timeout.clone().unwrap_or("10s".into()))?;
+    let listener = TcpListener::bind(&addr).await?;
+    tracing::info!("admin listening on {}", &addr);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.triggered() => break,
+        };
+        let runtime = Arc::clone(&runtime);
+        tokio::spawn(async move {
+            if let Err(e) = answer(
+                HttpConn::new(stream, admin_timeout),
+                &runtime,
+                admin_timeout,
+            )
+            .await
+            {
+                tracing::debug!("admin connection error from {}: {:?}", &peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn answer<S>(mut conn: HttpConn<S>, runtime: &Runtime, admin_timeout: Duration) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(head) = conn.read_request(Instant::now() + admin_timeout).await? else {
+        return Ok(());
+    };
+    let json = |status, body: Value| (status, "application/json", body.to_string());
+    let (status, content_type, body) = match (head.method.as_str(), head.path()) {
+        ("GET", "/metrics") => (
+            StatusCode::OK,
+            "text/plain; version=0.0.4",
+            METRICS.render(),
+        ),
+        ("GET", "/confs") => json(StatusCode::OK, json!(runtime.loaded().confs)),
+        ("GET", "/routes") => json(StatusCode::OK, routes(&runtime.loaded().routes)),
+        ("GET", "/health") => json(StatusCode::OK, health(&runtime.loaded().routes)),
+        ("GET", "/connections") => json(StatusCode::OK, connections(&runtime.loaded().routes)),
+        ("GET", "/version") => json(StatusCode::OK, version(runtime)),
+        ("POST", path) if path.starts_with("/targets/") => {
+            let (status, body) = rotate(&runtime.loaded().routes, path);
+            json(status, body)
+        }
+        ("POST", "/reload") => match runtime.reload().await {
+            Ok(()) => (StatusCode::OK, "application/json", json_detail("reloaded")?),
+            Err(e) => {
+                tracing::error!("reload failed: {:?}", e);
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "application/json",
+                    json_detail(&describe(&e))?,
+                )
+            }
+        },
+        _ => (
+            StatusCode::NOT_FOUND,
+            "application/json",
+            json_detail("not found")?,
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        status.as_str(),
+        status.canonical_reason().unwrap_or_default(),
+        content_type,
+        body.len(),
+        body
+    );
+    conn.stream.write_all(response.as_bytes()).await?;
+    conn.stream.shutdown().await?;
+    Ok(())
+}
+
+fn rules(rules: &[HostRule]) -> Vec<String> {
+    rules.iter().map(HostRule::to_string).collect()
+}
+
+fn address(target: &UpstreamTarget) -> String {
+    format!("{}:{}", &target.host, &target.port)
+}
+
+fn target(target: &UpstreamTarget) -> Value {
+    json!({
+        "host": target.host,
+        "port": target.port,
+        "http2": target.http2,
+        "tls": target.tls.is_some(),
+        "healthy": target.health.is_healthy(),
+        "state": target.rotation.get().as_str(),
+        "active": METRICS.upstream_active.with(&[&address(target)]).get(),
+    })
+}
+
+fn targets(targets: &[UpstreamTarget]) -> Vec<Value> {
+    targets.iter().map(target).collect()
+}
+
+fn route(route: &Route) -> Value {
+    let kind = match route {
+        _ if route.forward.is_some() => "forward",
+        _ if route.endpoints.is_some() => "http",
+        _ => "tcp",
+    };
+    let timeouts = route.timeouts;
+    json!({
+        "listen": route.listen,
+        "kind": kind,
+        "proxy_protocol": route.proxy_protocol,
+        "endpoints": route.paths,
+        "targets": targets(&route.targets),
+        "sni": route.sni.iter().map(|sni| json!({
+            "names": rules(&sni.names),
+            "alpn": sni.alpn,
+            "targets": targets(&sni.targets),
+        })).collect::<Vec<_>>(),
+        "forward": route.forward.as_ref().map(|policy| json!({
+            "allow": rules(&policy.allow),
+            "deny": rules(&policy.deny),
+        })),
+        "tls": route.tls.as_ref().map(|tls| json!({
+            "cert": tls.cert,
+            "key": tls.key,
+            "acme": tls.acme.is_some(),
+            "expires": tls.expires().map(|expires| format_rfc3339_seconds(expires).to_string()),
+        })),
+        "timeouts": {
+            "connect": format_duration(timeouts.connect).to_string(),
+            "client_header": format_duration(timeouts.client_header).to_string(),
+            "idle": format_duration(timeouts.idle).to_string(),
+            "upstream_response": format_duration(timeouts.upstream_response).to_string(),
+            "upgrade_idle": format_duration(timeouts.upgrade_idle).to_string(),
+        },
+        "request_id": {
+            "header": route.request_id.header,
+            "trust": route.request_id.trust,
+        },
+    })
+}
+
+/// The effective routes by port, as `GET /routes` and `--dry-run` show them.
+pub fn routes(routes: &[Arc<Route>]) -> Value {
+    let mut routes = routes.iter().collect::<Vec<_>>();
+    routes.sort_by_key(|r| r.listen);
+    Value::Array(routes.into_iter().map(|r| route(r)).collect())
+}
+
+fn health(routes: &[Arc<Route>]) -> Value {
+    let health = routes
+        .iter()
+        .map(|route| {
+            (
+                route.listen.to_string(),
+                Value::Array(route.all_targets().map(target).collect()),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    json!(health)
+}
+
+/// Answers `POST /targets/{host}:{port}/{drain,disable,enable}`, moving that
+/// target of every route to the new state.
+fn rotate(routes: &[Arc<Route>], path: &str) -> (StatusCode, Value) {
+    let not_found = |detail: String| (StatusCode::NOT_FOUND, json!({ "detail": detail }));
+    let Some((addr, action)) = path.trim_start_matches("/targets/").rsplit_once('/') else {
+        return not_found("not found".into());
+    };
+    let state = match action {
+        "drain" => TargetState::Draining,
+        "disable" => TargetState::Disabled,
+        "enable" => TargetState::Active,
+        _ => return not_found("not found".into()),
+    };
+    let mut listeners = vec![];
+    for route in routes {
+        for target in route.all_targets().filter(|target| address(target) == addr) {
+            if target.rotation.set(state) {
+                tracing::info!(
+                    "target {} on port {} is {}",
+                    addr,
+                    route.listen,
+                    state.as_str()
+                );
+            }
+            if state != TargetState::Active {
+                route.pool.evict(target);
+            }
+            METRICS.observe_state(target);
+            listeners.push(route.listen);
+        }
+    }
+    if listeners.is_empty() {
+        return not_found(format!("no target {}", addr));
+    }
+    listeners.sort();
+    listeners.dedup();
+    (
+        StatusCode::OK,
+        json!({ "target": addr, "state": state.as_str(), "listeners": listeners }),
+    )
+}
+
+fn connections(routes: &[Arc<Route>]) -> Value {
+    let listeners = routes
+        .iter()
+        .map(|route| {
+            let listen = route.listen.to_string();
+            let active = METRICS.connections_active.with(&[&listen]).get();
+            (listen, active)
+        })
+        .collect::<BTreeMap<_, _>>();
+    json!({
+        "active": listeners.values().sum::<i64>(),
+        "listeners": listeners,
+    })
+}
+
+fn version(runtime: &Runtime) -> Value {
+    let uptime = runtime.started.elapsed().unwrap_or_default();
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "build": if cfg!(debug_assertions) { "debug" } else { "release" },
+        "started": format_rfc3339_seconds(runtime.started).to_string(),
+        "uptime": format_duration(Duration::from_secs(uptime.as_secs())).to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    fn load() -> Result<(Runtime, mpsc::Receiver<Reload>)> {
+        let confs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&confs)?;
+        let (reloads, requests) = mpsc::channel(1);
+        Ok((Runtime::new(Loaded { confs, routes }, reloads), requests))
+    }
+
+    /// Sends `request` ("GET /path") to the admin API, returning the status and body.
+    async fn call(runtime: &Runtime, request: &str) -> Result<(u16, String)> {
+        let (mut client, server) = duplex(64 * 1024);
+        client
+            .write_all(format!("{} HTTP/1.1\r\nHost: admin\r\n\r\n", request).as_bytes())
+            .await?;
+        let timeout = Duration::from_secs(1);
+        answer(HttpConn::new(server, timeout), runtime, timeout).await?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        Ok((status, body.to_string()))
+    }
+
+    async fn json(runtime: &Runtime, request: &str) -> Result<Value> {
+        Ok(serde_json::from_str(&call(runtime, request).await?.1)?)
+    }
+
+    #[tokio::test]
+    async fn reports_routes_and_targets() -> Result<()> {
+        let (runtime, _) = load()?;
+        let routes = json(&runtime, "GET /routes").await?;
+        let listens = routes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|route| route["listen"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(listens.is_sorted());
+        let http = routes
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|route| route["listen"] == 5000)
+            .unwrap();
+        assert_eq!(http["kind"], "http");
+        assert_eq!(http["request_id"]["header"], "X-Correlation-Id");
+        assert_eq!(http["targets"][0]["state"], "active");
+
+        let health = json(&runtime, "GET /health").await?;
+        assert_eq!(health["5000"][0]["host"], "localhost");
+        assert_eq!(health["5000"][0]["healthy"], true);
+        let confs = json(&runtime, "GET /confs").await?;
+        assert!(confs
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|c| c["name"] == "one-ingress"));
+        assert_eq!(json(&runtime, "GET /version").await?["name"], "liteginx");
+        assert_eq!(call(&runtime, "GET /nope").await?.0, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drains_targets_across_reloads() -> Result<()> {
+        let (runtime, _) = load()?;
+        let drained = json(&runtime, "POST /targets/localhost:3000/drain").await?;
+        assert_eq!(drained["state"], "draining");
+        assert_eq!(drained["listeners"], json!([5000, 5005]));
+        assert_eq!(
+            json(&runtime, "GET /health").await?["5000"][0]["state"],
+            "draining"
+        );
+        assert!(METRICS
+            .render()
+            .contains("liteginx_upstream_state{target=\"localhost:3000\",state=\"draining\"} 1\n"));
+
+        let (reloaded, _) = load()?;
+        let loaded = reloaded.loaded();
+        runtime.keep_rotation(&loaded);
+        let target = loaded.routes.iter().find(|r| r.listen == 5000).unwrap();
+        assert_eq!(target.targets[0].rotation.get(), TargetState::Draining);
+
+        assert_eq!(
+            call(&runtime, "POST /targets/nowhere:1/drain").await?.0,
+            404
+        );
+        assert_eq!(
+            call(&runtime, "POST /targets/localhost:3000/pause")
+                .await?
+                .0,
+            404
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_failed_reloads() -> Result<()> {
+        let (runtime, mut requests) = load()?;
+        tokio::spawn(async move {
+            while let Some(reply) = requests.recv().await {
+                let _ = reply.send(Err(ProxyError::InvalidConf("a is defined twice".into())));
+            }
+        });
+        let (status, body) = call(&runtime, "POST /reload").await?;
+        assert_eq!(status, 422);
+        assert!(body.contains("a is defined twice"), "{}", body);
+        Ok(())
+    }
+}
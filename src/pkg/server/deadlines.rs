@@ -0,0 +1,91 @@
+use std::{
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Instant},
+};
+
+use crate::prelude::{ProxyError, Result};
+
+/// Idle deadline for a connection, shared between the tasks reading either side of it.
+pub struct Deadlines {
+    idle: Duration,
+    last_active: Mutex<Instant>,
+}
+
+impl Deadlines {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn last_active(&self) -> Instant {
+        *self.last_active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    pub async fn expired(&self) -> Result<()> {
+        loop {
+            let due = self.last_active() + self.idle;
+            sleep_until(due).await;
+            if Instant::now() >= self.last_active() + self.idle {
+                return Err(ProxyError::IdleTimeout);
+            }
+        }
+    }
+}
+
+/// Wraps a stream so every successful read counts as activity on `deadlines`.
+pub struct Tracked<'a, S> {
+    inner: S,
+    deadlines: &'a Deadlines,
+}
+
+impl<'a, S> Tracked<'a, S> {
+    pub fn new(inner: S, deadlines: &'a Deadlines) -> Self {
+        Self { inner, deadlines }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.deadlines.touch();
+        }
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,146 +1,718 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
 use crate::{
     pkg::{
+        access_log,
         conf::settings,
         server::{
-            helpers::{extract_path, http_404_response, match_prefix, rewrite_path},
-            upstream::ListenUpstream,
+            acme,
+            deadlines::{Deadlines, Tracked},
+            forward,
+            handoff::Listeners,
+            helpers::{
+                error_status, http_404_response, http_error_response, match_prefix, rewrite_path,
+            },
+            http::{Framing, HttpConn, RequestHead},
+            http2::{self, Rewind, PREFACE},
+            metrics::{Counted, METRICS},
+            pool::Pooled,
+            proxy_protocol::{read_header, ProxyAddrs},
+            shutdown::Shutdown,
+            sni,
+            tls::{client_name, negotiated_acme, negotiated_h2, ClientCert},
+            upstream::{unless_disabled, ListenUpstream},
         },
-        spec::routes::{Endpoint, ReceiverCh, Route, SenderCh},
+        spec::routes::{Route, TargetState, UpstreamTarget},
+        telemetry,
     },
     prelude::{ProxyError, Result},
 };
 use async_trait::async_trait;
 use humantime::parse_duration;
-use matchit::Router;
 use rand::seq::IndexedRandom;
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::mpsc,
-    task::JoinSet,
+    io::{copy_bidirectional_with_sizes, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, Instant},
 };
+use tracing::Instrument;
+
+const DEFAULT_TCP_BUFFER_SIZE: usize = 64 * 1024;
 
 #[async_trait]
 pub trait ListenDownstream<'a> {
-    async fn serve(&self) -> Result<()>;
-    async fn retry(&self) -> Result<()>;
-    async fn spawn_upstream(
-        &self,
-        client_tx: SenderCh,
-        target_tx: ReceiverCh,
-    ) -> Result<JoinSet<Result<()>>>;
+    async fn serve(self: Arc<Self>, listeners: Arc<Listeners>, shutdown: Shutdown) -> Result<()>;
+    async fn retry(self: Arc<Self>, listeners: Arc<Listeners>, shutdown: Shutdown) -> Result<()>;
+    async fn accept(
+        self: Arc<Self>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        shutdown: Shutdown,
+    ) -> Result<()>;
+    async fn tunnel<S>(&self, stream: S, addrs: ProxyAddrs) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send;
+    async fn passthrough(&self, stream: Counted<TcpStream>, addrs: ProxyAddrs) -> Result<()>;
+    fn target(&self) -> Result<&UpstreamTarget>;
+    async fn checkout(&self, target: &UpstreamTarget, addrs: ProxyAddrs) -> Result<Pooled>;
 }
 
 #[async_trait]
 impl<'a> ListenDownstream<'a> for Route {
-    async fn spawn_upstream(
-        &self,
-        client_tx: SenderCh,
-        target_rx: ReceiverCh,
-    ) -> Result<JoinSet<Result<()>>> {
-        let mut set = JoinSet::new();
-        let target = self
-            .targets
-            .choose(&mut rand::rng())
-            .ok_or(ProxyError::DownStreamServerEmptyTargets)?;
-        let target = target.clone();
-        set.spawn(async move { target.listen(client_tx, target_rx, 0).await });
-        Ok(set)
-    }
-
-    async fn retry(&self) -> Result<()> {
-        tokio::time::sleep(parse_duration(
+    fn target(&self) -> Result<&UpstreamTarget> {
+        pick(&self.targets)
+    }
+
+    async fn checkout(&self, target: &UpstreamTarget, addrs: ProxyAddrs) -> Result<Pooled> {
+        self.pool.checkout(target, addrs, self.timeouts).await
+    }
+
+    /// Tunnels a TLS connection without terminating it, to the targets of the
+    /// first `sni` route matching its ClientHello or the route's own otherwise.
+    async fn passthrough(&self, mut stream: Counted<TcpStream>, addrs: ProxyAddrs) -> Result<()> {
+        let started = Instant::now();
+        let r = async {
+            let deadline = started + self.timeouts.client_header;
+            let (hello, buffered) = sni::peek(&mut stream, deadline).await?;
+            let targets = self
+                .sni
+                .iter()
+                .find(|route| route.matches(&hello))
+                .map_or(&self.targets, |route| &route.targets);
+            tracing::debug!(
+                "passing through tls for {:?}, alpn {:?}",
+                &hello.server_name,
+                &hello.alpn
+            );
+            telemetry::record_host(hello.server_name.as_deref());
+            let target = pick(targets)?;
+            telemetry::record_target(target);
+            let _relaying = METRICS.relaying(target);
+            let mut upstream = target.connect(addrs, self.timeouts).await?;
+            upstream.write_all(&buffered).await?;
+            let spliced = splice(&mut stream, &mut upstream, self.timeouts.idle);
+            Ok::<_, ProxyError>(unless_disabled(target, spliced).await)
+        }
+        .await;
+        log_session(self.listen, addrs, started);
+        r.map(|r| tracing::debug!("tls passthrough closed: {:?}", &r))
+    }
+
+    async fn tunnel<S>(&self, mut stream: S, addrs: ProxyAddrs) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let started = Instant::now();
+        let r = async {
+            let target = self.target()?;
+            telemetry::record_target(target);
+            let _relaying = METRICS.relaying(target);
+            let mut upstream = target.connect(addrs, self.timeouts).await?;
+            let spliced = splice(&mut stream, &mut upstream, self.timeouts.idle);
+            Ok::<_, ProxyError>(unless_disabled(target, spliced).await)
+        }
+        .await;
+        log_session(self.listen, addrs, started);
+        r.map(|r| tracing::debug!("tunnel closed: {:?}", &r))
+    }
+
+    async fn retry(self: Arc<Self>, listeners: Arc<Listeners>, shutdown: Shutdown) -> Result<()> {
+        let heartbeat = parse_duration(
             &settings
                 .upstream_reconnect_heartbeat
                 .clone()
                 .unwrap_or("10s".into()),
-        )?)
-        .await;
-        self.serve().await?;
+        )?;
+        tokio::select! {
+            _ = tokio::time::sleep(heartbeat) => self.serve(listeners, shutdown).await?,
+            _ = shutdown.triggered() => {}
+        }
         Ok(())
     }
 
-    async fn serve(&self) -> Result<()> {
+    async fn accept(
+        self: Arc<Self>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        let mut addrs = ProxyAddrs {
+            source: peer,
+            destination: stream.local_addr()?,
+        };
+        let mut stream = Counted::new(stream, self.listen);
+        if self.proxy_protocol {
+            let header = timeout(self.timeouts.client_header, read_header(&mut stream))
+                .await
+                .map_err(|_| ProxyError::ClientHeaderTimeout)??;
+            if let Some(proxied) = header {
+                addrs = proxied;
+            }
+        }
+        tracing::debug!(
+            "accepted connection from {} on port {}",
+            &addrs.source,
+            &self.listen
+        );
+        if !self.sni.is_empty() {
+            return self.passthrough(stream, addrs).await;
+        }
+        let Some(ref tls) = self.tls else {
+            return dispatch(self, stream, addrs, None, &shutdown, false).await;
+        };
+        let stream = timeout(self.timeouts.client_header, tls.accept(stream))
+            .await
+            .map_err(|_| ProxyError::ClientHeaderTimeout)??;
+        if negotiated_acme(&stream) {
+            // the handshake itself answered the TLS-ALPN-01 challenge
+            return Ok(());
+        }
+        let h2 = negotiated_h2(&stream);
+        let cert = ClientCert::from_stream(&stream).map(Arc::new);
+        dispatch(self, stream, addrs, cert, &shutdown, h2).await
+    }
+
+    async fn serve(self: Arc<Self>, listeners: Arc<Listeners>, shutdown: Shutdown) -> Result<()> {
         if let Err(e) = async {
-            let listener = TcpListener::bind(format!("0.0.0.0:{}", self.listen)).await?;
+            let listener = listeners.bind(self.listen)?;
             tracing::debug!("bound to port: {}", &self.listen);
             loop {
-                let (mut stream, _) = listener.accept().await?;
-                let endpoints = self.endpoints.clone();
-                let (client_tx, client_rx) = mpsc::channel::<Vec<u8>>(1);
-                let (target_tx, target_rx) = mpsc::channel::<Vec<u8>>(1);
-                let mut set = self.spawn_upstream(client_tx.clone(), target_rx).await?;
-                set.spawn(async move {
-                    handle(endpoints, &mut stream, target_tx, client_tx, client_rx).await
-                });
-                tokio::spawn(async move {
-                    set.join_all().await;
-                });
+                let (stream, peer) = tokio::select! {
+                    accepted = listener.accept() => accepted?,
+                    _ = shutdown.triggered() => break,
+                };
+                let route = Arc::clone(&self);
+                let conn_shutdown = shutdown.clone();
+                let span = telemetry::connection_span(self.listen, &peer.to_string());
+                shutdown.spawn(
+                    async move {
+                        let listen = route.listen.to_string();
+                        METRICS.connections_accepted.with(&[&listen]).inc();
+                        let _active = METRICS.connections_active.with(&[&listen]).track();
+                        let accepted = route.accept(stream, peer, conn_shutdown);
+                        if let Err(e) = telemetry::recording(accepted).await {
+                            tracing::error!("connection error from {}: {:?}", &peer, e);
+                        }
+                    }
+                    .instrument(span),
+                );
             }
-            #[allow(unreachable_code)]
+            tracing::info!("stopped accepting on port: {}", &self.listen);
             Ok::<(), ProxyError>(())
         }
         .await
         {
             tracing::error!("serve error: {:?}", e);
-            self.retry().await?;
+            self.retry(listeners, shutdown).await?;
         }
         Ok(())
     }
 }
 
-async fn handle<'a>(
-    endpoints: Option<Router<Endpoint>>,
-    mut stream: &'a mut TcpStream,
-    target_tx: SenderCh,
-    client_tx: SenderCh,
-    mut client_rx: ReceiverCh,
-) -> Result<()> {
-    let mut buffer = vec![1; 1024];
-    tracing::debug!("handling connection...");
-    let (mut reader, mut writer) = split(&mut stream);
+/// Picks a random healthy target in rotation out of `targets`.
+fn pick(targets: &[UpstreamTarget]) -> Result<&UpstreamTarget> {
+    if targets.is_empty() {
+        return Err(ProxyError::DownStreamServerEmptyTargets);
+    }
+    let available: Vec<&UpstreamTarget> = targets
+        .iter()
+        .filter(|target| target.in_rotation())
+        .collect();
+    available
+        .choose(&mut rand::rng())
+        .copied()
+        .ok_or(ProxyError::NoHealthyTargets)
+}
+
+/// Logs a TCP session from `addrs` that started at `started`.
+fn log_session(listen: u16, addrs: ProxyAddrs, started: Instant) {
+    let mut record = telemetry::take_record();
+    record.protocol = Some("TCP".into());
+    access_log::log(listen, addrs.source, &record, started.elapsed());
+}
+
+/// Copies bytes both ways between `client` and `upstream` until either side
+/// closes or neither sent anything for `idle`.
+pub async fn splice<A, B>(client: &mut A, upstream: &mut B, idle: Duration) -> Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let buffer_size = settings.tcp_buffer_size.unwrap_or(DEFAULT_TCP_BUFFER_SIZE);
+    let deadlines = Deadlines::new(idle);
+    let mut client = Tracked::new(client, &deadlines);
+    let mut target = Tracked::new(upstream, &deadlines);
     tokio::select! {
-        r = async{
-            loop{
-                let n = reader.read(&mut buffer).await?;
-                if n == 0 {
-                    break;
-                }
-                let mut body = buffer[..n].to_vec();
-                if let Some(ref router) = endpoints{
-                    let path = extract_path(&body);
-                    match match_prefix(&router, &path){
-                        Some(endpoint) => {
-                            if let Some(ref rewrite) = endpoint.rewrite{
-                                let rewrite_from = format!("/{}", &path);
-                                tracing::info!("rewriting path: {:?} to {:?}", &rewrite_from, &rewrite);
-                                body = rewrite_path(&body, rewrite_from.into(), rewrite.as_str().into());
-                            }
-                            target_tx.send(body).await?;
-                        },
-                        None => {
-                            tracing::warn!("path {} not found", &path);
-                            client_tx.send(http_404_response()?.into()).await?;
-                        }
-                    }
-                }else{
-                    target_tx.send(body).await?;
-                }
-                tracing::debug!("received downstream message from client, sent to upstream target");
-            }
-            Err::<(), ProxyError>(ProxyError::DownStreamEndOfBytes)
-        } => {
-            tracing::debug!("downstream reader closed: {:?}", &r);
+        r = copy_bidirectional_with_sizes(&mut client, &mut target, buffer_size, buffer_size) => {
+            r.map(|(sent, received)| {
+                tracing::debug!("tcp stream closed, sent {} bytes, received {} bytes", sent, received);
+            }).map_err(ProxyError::from)
         },
-        _ = async{
-            while let Some(msg) = client_rx.recv().await{
-                writer.write_all(&msg).await?;
-                tracing::debug!("received upstream message from target, sent downstream");
+        r = deadlines.expired() => r,
+    }
+}
+
+async fn dispatch<S>(
+    route: Arc<Route>,
+    stream: S,
+    addrs: ProxyAddrs,
+    cert: Option<Arc<ClientCert>>,
+    shutdown: &Shutdown,
+    h2: bool,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match (&route.endpoints, h2) {
+        _ if route.forward.is_some() => handle(route, stream, addrs, cert, shutdown).await,
+        (None, _) => route.tunnel(stream, addrs).await,
+        (Some(_), true) => http2::serve(route, stream, addrs, cert, shutdown).await,
+        (Some(_), false) => handle(route, stream, addrs, cert, shutdown).await,
+    }
+}
+
+async fn handle<S>(
+    route: Arc<Route>,
+    stream: S,
+    addrs: ProxyAddrs,
+    cert: Option<Arc<ClientCert>>,
+    shutdown: &Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let timeouts = route.timeouts;
+    let mut client = HttpConn::new(stream, timeouts.idle);
+    tracing::debug!("handling connection...");
+    let mut header_deadline = Instant::now() + timeouts.client_header;
+    if client.starts_with(PREFACE, header_deadline).await? {
+        let (stream, buffered) = client.into_parts();
+        return http2::serve(route, Rewind::new(stream, buffered), addrs, cert, shutdown).await;
+    }
+    let mut request_id = None;
+    let r = loop {
+        let head = tokio::select! {
+            head = client.read_request(header_deadline) => head,
+            _ = shutdown.triggered(), if !client.has_buffered() => break Ok(()),
+        };
+        let mut head = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let id = route
+            .request_id
+            .resolve(head.header(&route.request_id.header));
+        head.set_header(&route.request_id.header, &id);
+        let span = telemetry::request_span(
+            route.listen,
+            &head.method,
+            head.path(),
+            &id,
+            telemetry::extract(&head),
+        );
+        let started = Instant::now();
+        let r = exchange(&route, &mut client, &mut head, addrs, cert.as_deref())
+            .instrument(span.clone())
+            .await;
+        let mut record = telemetry::take_record();
+        record.request_id = Some(id.clone());
+        request_id = Some(id);
+        if let Some((status, _)) = r.as_ref().err().and_then(error_status) {
+            span.record("http.response.status_code", status.as_u16());
+            record.status = Some(status.as_u16());
+        }
+        METRICS.observe_request(route.listen, &record, started.elapsed());
+        access_log::log(route.listen, addrs.source, &record, started.elapsed());
+        match r {
+            Ok(true) if !shutdown.is_triggered() => {
+                header_deadline = Instant::now() + timeouts.idle;
             }
-            Err::<(), ProxyError>(ProxyError::UpStreamEndOfBytes)
-        } => {
-            tracing::debug!("downstream listener closed");
-        },
+            Ok(_) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    tracing::debug!("downstream connection closed: {:?}", &r);
+    let response = match r {
+        Err(ref e) => {
+            let echo = request_id
+                .as_deref()
+                .map(|id| (route.request_id.header.as_str(), id));
+            http_error_response(e, echo.as_slice())?
+        }
+        Ok(_) => None,
+    };
+    if let Some(response) = response {
+        client.stream.write_all(response.as_bytes()).await?;
     }
+    // the client may already be gone, nothing left to tell it
+    let _ = client.stream.shutdown().await;
     Ok(())
 }
+
+/// Tunnels an upgraded connection until either side closes it or it idles for
+/// `upgrade_idle`, starting with whatever either side sent along with the handshake.
+async fn upgraded<S>(route: &Route, client: &mut HttpConn<S>, mut conn: Pooled) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let upstream = &mut conn.conn;
+    upstream.stream.write_all(&client.take_buffered()).await?;
+    client.stream.write_all(&upstream.take_buffered()).await?;
+    let r = splice(
+        &mut client.stream,
+        &mut upstream.stream,
+        route.timeouts.upgrade_idle,
+    )
+    .await;
+    tracing::debug!("upgraded connection closed: {:?}", &r);
+    Ok(())
+}
+
+/// Forwards one request and its response, returning whether the client
+/// connection can carry another request. An upstream connection is only checked
+/// out once the request matched an endpoint, and goes back to the pool as soon
+/// as its response was relayed. Errors raised before any response bytes reached
+/// the client are answered by `handle`.
+async fn exchange<S>(
+    route: &Route,
+    client: &mut HttpConn<S>,
+    head: &mut RequestHead,
+    addrs: ProxyAddrs,
+    cert: Option<&ClientCert>,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let keep_alive = head.keep_alive();
+    // framed before stripping, which drops the Content-Length of chunked requests
+    let framing = head.framing()?;
+    telemetry::record_request(
+        &head.method,
+        &head.target,
+        &format!("HTTP/1.{}", head.version),
+        None,
+        head,
+    );
+    let upgrade = head.upgrade().map(String::from);
+    head.strip_hop_by_hop();
+    if let Some(ref protocol) = upgrade {
+        head.set_header("Connection", "Upgrade");
+        head.set_header("Upgrade", protocol);
+    }
+    if let Some(ref policy) = route.forward {
+        return forward::exchange(route, policy, client, head, framing, keep_alive, addrs).await;
+    }
+    if let Some(response) = acme::http01_response(head.path()) {
+        client.stream.write_all(response.as_bytes()).await?;
+        return Ok(false);
+    }
+
+    let Some(endpoint) = route
+        .endpoints
+        .as_ref()
+        .and_then(|router| match_prefix(router, head.path()))
+    else {
+        tracing::warn!("path {} not found", head.path());
+        telemetry::record_status(404);
+        let header = route.request_id.header.as_str();
+        let echo = head.header(header).map(|id| (header, id));
+        client
+            .stream
+            .write_all(http_404_response(echo.as_slice())?.as_bytes())
+            .await?;
+        return Ok(false);
+    };
+    telemetry::record_route(&head.method, &endpoint.path);
+    if !endpoint.admits(cert) {
+        return Err(ProxyError::ClientNotAllowed(client_name(cert)));
+    }
+    head.target = rewrite_path(endpoint, &head.target);
+    telemetry::record_rewrite(&head.target);
+    head.push_header("X-Forwarded-For", &addrs.source.ip().to_string());
+    forward_client_cert(route, head, cert);
+
+    let target = route.target()?;
+    telemetry::record_target(target);
+    let _relaying = METRICS.relaying(target);
+    continue_expected(client, head).await?;
+    telemetry::inject(head);
+    unless_disabled(
+        target,
+        relay(route, target, client, head, framing, keep_alive, addrs),
+    )
+    .await
+}
+
+/// Asks a client waiting on `Expect: 100-continue` for its body. Only called
+/// once the request is bound for a target, so requests refused before then are
+/// answered without reading a body the client never had to send.
+pub async fn continue_expected<S>(client: &mut HttpConn<S>, head: &mut RequestHead) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if head
+        .header("expect")
+        .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    {
+        client
+            .stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await?;
+        head.remove_header("expect");
+    }
+    Ok(())
+}
+
+/// Replaces whatever the client sent in the client certificate header with the
+/// identity its certificate was verified as, if any.
+fn forward_client_cert(route: &Route, head: &mut RequestHead, cert: Option<&ClientCert>) {
+    let header = route.client_header();
+    head.remove_header(header);
+    if let Some(cert) = cert {
+        head.set_header(header, &cert.header_value());
+    }
+}
+
+/// Sends `head` and its body, delimited by `framing` as the client sent it, to
+/// `target` and relays the response back, tunnelling the connection from then
+/// on if the target agreed to an upgrade.
+pub async fn relay<S>(
+    route: &Route,
+    target: &UpstreamTarget,
+    client: &mut HttpConn<S>,
+    head: &RequestHead,
+    framing: Framing,
+    mut keep_alive: bool,
+    addrs: ProxyAddrs,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let upgrade = head.upgrade().map(String::from);
+    if target.http2 && upgrade.is_some() {
+        return Err(ProxyError::UpgradeUnsupported);
+    }
+    if target.http2 {
+        return http2::from_h1(route, target, client, head, framing, addrs).await;
+    }
+    let mut conn = route.checkout(target, addrs).await?;
+    conn.conn.stream.write_all(&head.encode()).await?;
+    client.copy_body(framing, &mut conn.conn.stream).await?;
+    tracing::debug!("forwarded request to upstream target");
+
+    let response_deadline = Instant::now() + route.timeouts.upstream_response;
+    let mut response = conn.conn.read_response(response_deadline).await?;
+    while response.is_informational() && response.status != 101 {
+        client.stream.write_all(&response.encode()).await?;
+        response = conn.conn.read_response(response_deadline).await?;
+    }
+    telemetry::record_status(response.status);
+    if response.status == 101 {
+        let protocol =
+            response
+                .upgrade()
+                .map(String::from)
+                .or(upgrade)
+                .ok_or(ProxyError::MalformedHttp(
+                    "unrequested protocol switch".into(),
+                ))?;
+        response.strip_hop_by_hop();
+        route.request_id.echo(head, &mut response);
+        response.set_header("Connection", "Upgrade");
+        response.set_header("Upgrade", &protocol);
+        client.stream.write_all(&response.encode()).await?;
+        tracing::debug!("upgraded connection to {}", &protocol);
+        return upgraded(route, client, conn).await.map(|_| false);
+    }
+    let response_framing = response.framing(&head.method)?;
+    let upstream_keep_alive = response.keep_alive() && response_framing != Framing::UntilClose;
+    keep_alive &= response_framing != Framing::UntilClose;
+    response.strip_hop_by_hop();
+    route.request_id.echo(head, &mut response);
+    if !keep_alive {
+        response.set_header("Connection", "close");
+    }
+    client.stream.write_all(&response.encode()).await?;
+    conn.conn
+        .copy_body(response_framing, &mut client.stream)
+        .await?;
+    tracing::debug!("received upstream response from target, sent downstream");
+    if upstream_keep_alive && target.rotation.get() == TargetState::Active {
+        route.pool.release(conn);
+    }
+    Ok(keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, AsyncReadExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::pkg::{
+        server::{http::Header, tls::DEFAULT_CLIENT_HEADER},
+        spec::config::IngressConf,
+    };
+
+    #[tokio::test]
+    async fn drops_silent_proxy_protocol_peer() -> Result<()> {
+        let conf: IngressConf = serde_yaml::from_str(
+            "name: silent\nspec:\n- kind: tcp\n  listen: 6100\n  proxy_protocol: true\n  timeouts: {client_header: 50ms}\n  targets: [{host: localhost, port: 4000}]\ntls: {enabled: false}\n",
+        )
+        .unwrap();
+        let route = Route::new(&[conf])?.remove(0);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let _silent = TcpStream::connect(listener.local_addr()?).await?;
+        let (stream, peer) = listener.accept().await?;
+        let accepted = route.accept(stream, peer, Shutdown::default());
+        let r = timeout(Duration::from_secs(1), accepted)
+            .await
+            .expect("silent peer kept the connection open");
+        assert!(matches!(r, Err(ProxyError::ClientHeaderTimeout)));
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_spoofed_client_cert_header() {
+        let route = Route::default();
+        let mut head = RequestHead {
+            method: "GET".into(),
+            target: "/".into(),
+            version: 1,
+            headers: vec![Header {
+                name: "x-forwarded-client-cert".into(),
+                value: "Subject=\"CN=admin\"".into(),
+            }],
+        };
+        let mut stripped = head.clone();
+        forward_client_cert(&route, &mut stripped, None);
+        assert_eq!(stripped.header(DEFAULT_CLIENT_HEADER), None);
+
+        let cert = ClientCert {
+            subject: "CN=client".into(),
+            ..Default::default()
+        };
+        forward_client_cert(&route, &mut head, Some(&cert));
+        assert_eq!(
+            head.header(DEFAULT_CLIENT_HEADER),
+            Some("Subject=\"CN=client\"")
+        );
+        assert_eq!(head.headers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_request_framed_twice() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let conf: IngressConf = serde_yaml::from_str(&format!(
+            "name: smuggle\nspec:\n- kind: http\n  path: /\n  listen: 6101\n  targets: [{{host: 127.0.0.1, port: {}}}]\ntls: {{enabled: false}}\n",
+            upstream.local_addr()?.port()
+        ))
+        .unwrap();
+        let route = Route::new(&[conf])?.remove(0);
+        let addrs = ProxyAddrs {
+            source: "127.0.0.1:50000".parse().unwrap(),
+            destination: "127.0.0.1:6101".parse().unwrap(),
+        };
+        let (mut client, server) = duplex(64 * 1024);
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: a\r\n\r\n",
+            )
+            .await?;
+        let shutdown = Shutdown::default();
+        timeout(
+            Duration::from_secs(1),
+            handle(route, server, addrs, None, &shutdown),
+        )
+        .await
+        .expect("connection kept open")?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        assert_eq!(response.matches("HTTP/1.1").count(), 1);
+        assert!(timeout(Duration::from_millis(50), upstream.accept())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn continues_only_routed_requests() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let conf: IngressConf = serde_yaml::from_str(&format!(
+            "name: continue\nspec:\n- kind: http\n  path: /a\n  listen: 6102\n  targets: [{{host: 127.0.0.1, port: {}}}]\ntls: {{enabled: false}}\n",
+            upstream.local_addr()?.port()
+        ))
+        .unwrap();
+        let route = Route::new(&[conf])?.remove(0);
+        let addrs = ProxyAddrs {
+            source: "127.0.0.1:50000".parse().unwrap(),
+            destination: "127.0.0.1:6102".parse().unwrap(),
+        };
+        let shutdown = Shutdown::default();
+
+        // unrouted requests are refused without being asked for their body
+        let (mut client, server) = duplex(64 * 1024);
+        client
+            .write_all(
+                b"POST /b HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            )
+            .await?;
+        timeout(
+            Duration::from_secs(1),
+            handle(Arc::clone(&route), server, addrs, None, &shutdown),
+        )
+        .await
+        .expect("connection kept open")?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+
+        // routed ones are, and the expectation isn't passed on
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await?;
+            let mut request = Vec::new();
+            while !request.ends_with(b"hello") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await?;
+                assert!(n > 0, "request cut short");
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .await?;
+            Ok::<_, ProxyError>(String::from_utf8_lossy(&request).into_owned())
+        });
+        let (mut client, server) = duplex(64 * 1024);
+        client
+            .write_all(
+                b"POST /a HTTP/1.1\r\nHost: a\r\nConnection: close\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            )
+            .await?;
+        let proxied = tokio::spawn(async move {
+            timeout(
+                Duration::from_secs(1),
+                handle(route, server, addrs, None, &shutdown),
+            )
+            .await
+            .expect("connection kept open")
+        });
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).await?;
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        client.write_all(b"hello").await?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 204 "), "{}", response);
+        let request = upstream.await.unwrap()?;
+        assert!(
+            !request.to_ascii_lowercase().contains("expect"),
+            "{}",
+            request
+        );
+        proxied.await.unwrap()
+    }
+}
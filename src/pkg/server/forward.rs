@@ -0,0 +1,195 @@
+use std::net::{IpAddr, SocketAddr};
+
+use http::uri::{Authority, Scheme, Uri};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+};
+
+use crate::{
+    pkg::{
+        server::{
+            downstream::{continue_expected, relay, splice},
+            http::{Framing, HttpConn, RequestHead},
+            proxy_protocol::ProxyAddrs,
+            upstream::ListenUpstream,
+        },
+        spec::routes::{ForwardPolicy, Route, UpstreamTarget},
+        telemetry,
+    },
+    prelude::{ProxyError, Result},
+};
+
+/// The target for a request to `host` and `port`, if `policy` allows it. Deny
+/// rules are also checked against the addresses `host` resolves to and, for an
+/// address, against those of the names they deny, so neither side can be
+/// written another way to get around them. The target connects to the checked
+/// addresses, as resolving `host` again could give others.
+async fn destination(policy: &ForwardPolicy, host: &str, port: u16) -> Result<UpstreamTarget> {
+    let denied = || {
+        tracing::warn!("forwarding to {}:{} denied", host, port);
+        ProxyError::ForwardDenied(format!("{}:{}", host, port))
+    };
+    if !policy.permits(host, port) {
+        return Err(denied());
+    }
+    let resolved = resolve(host, port).await?;
+    if resolved
+        .iter()
+        .any(|addr| policy.denies(&addr.ip().to_string(), port))
+    {
+        return Err(denied());
+    }
+    if host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() {
+        for rule in &policy.deny {
+            if rule.host.starts_with('*') || rule.port.is_some_and(|p| p != port) {
+                continue;
+            }
+            // names that don't resolve deny no address
+            let denied_addrs = resolve(&rule.host, port).await.unwrap_or_default();
+            if denied_addrs.iter().any(|addr| resolved.contains(addr)) {
+                return Err(denied());
+            }
+        }
+    }
+    Ok(UpstreamTarget {
+        host: host.to_string(),
+        port,
+        resolved,
+        ..Default::default()
+    })
+}
+
+/// The addresses `host` resolves to, with IPs in canonical form. Names that
+/// don't resolve to any are an error.
+async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let unresolved = |e: String| {
+        ProxyError::UpstreamConnectionRefused(format!("could not resolve {}: {}", host, e))
+    };
+    let addrs = lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|e| unresolved(e.to_string()))?
+        .map(|addr| SocketAddr::new(addr.ip().to_canonical(), port))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(unresolved("no addresses".into()));
+    }
+    Ok(addrs)
+}
+
+/// Handles one request to a forward proxy route, returning whether the client
+/// connection can carry another request. `CONNECT` requests open a tunnel to
+/// the destination they name, absolute-form requests are relayed to theirs.
+pub async fn exchange<S>(
+    route: &Route,
+    policy: &ForwardPolicy,
+    client: &mut HttpConn<S>,
+    head: &mut RequestHead,
+    framing: Framing,
+    keep_alive: bool,
+    addrs: ProxyAddrs,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if head.method.eq_ignore_ascii_case("CONNECT") {
+        let authority: Authority = head
+            .target
+            .parse()
+            .map_err(|_| ProxyError::MalformedHttp("invalid CONNECT target".into()))?;
+        let port = authority.port_u16().ok_or(ProxyError::MalformedHttp(
+            "CONNECT target without port".into(),
+        ))?;
+        let target = destination(policy, authority.host(), port).await?;
+        let mut upstream = target.open(addrs, route.timeouts).await?;
+        client
+            .stream
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        upstream.write_all(&client.take_buffered()).await?;
+        let r = splice(&mut client.stream, &mut upstream, route.timeouts.idle).await;
+        tracing::debug!("tunnel to {} closed: {:?}", &head.target, &r);
+        return Ok(false);
+    }
+
+    let uri: Uri = head
+        .target
+        .parse()
+        .map_err(|_| ProxyError::MalformedHttp("invalid request target".into()))?;
+    let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) else {
+        return Err(ProxyError::MalformedHttp(
+            "forward proxy requests need an absolute URI".into(),
+        ));
+    };
+    if scheme != &Scheme::HTTP {
+        return Err(ProxyError::MalformedHttp(format!(
+            "unsupported scheme {}",
+            scheme
+        )));
+    }
+    let target = destination(policy, authority.host(), authority.port_u16().unwrap_or(80)).await?;
+    let host = match authority.port() {
+        Some(port) => format!("{}:{}", authority.host(), port),
+        None => authority.host().to_string(),
+    };
+    head.target = uri
+        .path_and_query()
+        .map_or("/".into(), |p| p.as_str().to_string());
+    head.set_header("Host", &host);
+    head.remove_header("Proxy-Authorization");
+    head.push_header("X-Forwarded-For", &addrs.source.ip().to_string());
+    tracing::debug!("forwarding {} {} to {}", &head.method, &head.target, &host);
+    telemetry::record_target(&target);
+    continue_expected(client, head).await?;
+    telemetry::inject(head);
+    relay(route, &target, client, head, framing, keep_alive, addrs).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::spec::config::ForwardConf;
+
+    #[tokio::test]
+    async fn denies_names_and_addresses_alike() -> Result<()> {
+        let policy = |deny: &str| {
+            ForwardConf {
+                allow: vec!["*".into()],
+                deny: vec![deny.into()],
+            }
+            .load()
+        };
+        // a denied name, reached through its address
+        let by_name = policy("localhost")?;
+        assert!(destination(&by_name, "127.0.0.1", 80).await.is_err());
+        assert!(destination(&by_name, "[::ffff:127.0.0.1]", 80)
+            .await
+            .is_err());
+        // a denied address, reached through a name for it
+        let by_address = policy("127.0.0.1")?;
+        assert!(destination(&by_address, "localhost", 80).await.is_err());
+        assert!(destination(&by_address, "10.0.0.1", 80).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connects_to_checked_addresses() -> Result<()> {
+        let policy = ForwardConf {
+            allow: vec!["*".into()],
+            deny: vec!["10.0.0.1".into()],
+        }
+        .load()?;
+        let target = destination(&policy, "localhost", 8080).await?;
+        assert!(!target.resolved.is_empty());
+        assert!(target
+            .resolved
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 8080));
+        // names that don't resolve are refused rather than let through
+        assert!(matches!(
+            destination(&policy, "nowhere.invalid", 80).await,
+            Err(ProxyError::UpstreamConnectionRefused(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    io::ErrorKind,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    sync::Mutex,
+    time::Duration,
+};
+
+use sendfd::{RecvWithFd, SendWithFd};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UnixListener, UnixStream},
+    time::timeout,
+};
+
+use crate::{
+    pkg::conf::settings,
+    prelude::{ProxyError, Result},
+};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+const MAX_HANDOFF_FDS: usize = 253;
+/// Sent by the new process to ask for the listeners, so that merely connecting
+/// to the socket, as when checking it is in use, hands nothing over.
+const HANDOFF_REQUEST: &[u8] = b"listeners\n";
+const HANDOFF_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listening sockets by port. Sockets inherited from systemd or from a previous
+/// liteginx process are reused instead of binding, and a duplicate of every
+/// socket in use is kept so it can be handed to the next process on upgrade.
+#[derive(Default)]
+pub struct Listeners {
+    inherited: Mutex<HashMap<u16, std::net::TcpListener>>,
+    active: Mutex<HashMap<u16, OwnedFd>>,
+}
+
+impl Listeners {
+    /// Collects the `systemd_fds` sockets passed via systemd socket activation,
+    /// as counted by [`take_systemd_fds`], and, when `upgrade` is set, the
+    /// sockets handed over by the process behind `socket`.
+    pub async fn inherit(systemd_fds: RawFd, upgrade: bool, socket: Option<&str>) -> Result<Self> {
+        let mut inherited = systemd_listeners(systemd_fds)?;
+        if upgrade {
+            let socket = socket.ok_or(ProxyError::HandoffFailed(
+                "upgrade_socket is not set".into(),
+            ))?;
+            inherited.extend(receive(socket).await?);
+        }
+        for port in inherited.keys() {
+            tracing::info!("inherited listener for port: {}", port);
+        }
+        Ok(Self {
+            inherited: Mutex::new(inherited),
+            ..Default::default()
+        })
+    }
+
+    pub fn bind(&self, port: u16) -> Result<TcpListener> {
+        let listener = match lock(&self.inherited).remove(&port) {
+            Some(listener) => listener,
+            None => {
+                let address = settings.listen_address.as_deref().unwrap_or("0.0.0.0");
+                std::net::TcpListener::bind((address, port))?
+            }
+        };
+        listener.set_nonblocking(true)?;
+        lock(&self.active).insert(port, listener.as_fd().try_clone_to_owned()?);
+        TcpListener::from_std(listener).map_err(ProxyError::from)
+    }
+
+    /// Drops inherited sockets for ports no route listens on anymore.
+    pub fn release_unused(&self, ports: &[u16]) {
+        lock(&self.inherited).retain(|port, _| {
+            let used = ports.contains(port);
+            if !used {
+                tracing::info!("closing inherited listener for unconfigured port: {}", port);
+            }
+            used
+        });
+    }
+
+    /// Takes back the sockets in use as if inherited, for routes loaded anew to
+    /// keep listening on them while the ones they replace drain.
+    pub fn recycle(&self) {
+        let active = std::mem::take(&mut *lock(&self.active));
+        lock(&self.inherited).extend(
+            active
+                .into_iter()
+                .map(|(port, fd)| (port, std::net::TcpListener::from(fd))),
+        );
+    }
+
+    /// Waits for a new liteginx process on `socket` and passes it every active
+    /// listening socket. Resolves once a handoff succeeded so the caller can drain.
+    /// Only processes of the same user are handed anything.
+    pub async fn serve_handoff(&self, socket: &str) -> Result<()> {
+        let server = bind_handoff(socket).await?;
+        tracing::debug!("waiting for upgrades on: {}", socket);
+        loop {
+            let (mut stream, _) = server.accept().await?;
+            match self.hand_over(&mut stream).await {
+                Ok(count) => {
+                    tracing::info!("handed {} listeners over to new process", count);
+                    // closed before the connection, which the new process waits on
+                    // to take over the socket
+                    drop(server);
+                    return Ok(());
+                }
+                Err(e) => tracing::error!("listener handoff failed: {:?}", e),
+            }
+        }
+    }
+
+    async fn hand_over(&self, stream: &mut UnixStream) -> Result<usize> {
+        let peer = stream.peer_cred()?;
+        // SAFETY: getuid has no preconditions and cannot fail
+        let uid = unsafe { libc::getuid() };
+        if peer.uid() != uid {
+            return Err(ProxyError::HandoffFailed(format!(
+                "refusing peer of uid {}, pid {:?}",
+                peer.uid(),
+                peer.pid()
+            )));
+        }
+        let mut request = [0u8; HANDOFF_REQUEST.len()];
+        match timeout(HANDOFF_REQUEST_TIMEOUT, stream.read_exact(&mut request)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(ProxyError::HandoffFailed(
+                    "peer closed without asking for listeners".into(),
+                ));
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(ProxyError::HandoffFailed("no request from peer".into())),
+        }
+        if request != HANDOFF_REQUEST {
+            return Err(ProxyError::HandoffFailed("unexpected request".into()));
+        }
+        self.send(stream).await
+    }
+
+    async fn send(&self, stream: &UnixStream) -> Result<usize> {
+        let (ports, fds): (Vec<u16>, Vec<RawFd>) = {
+            let active = lock(&self.active);
+            active
+                .iter()
+                .map(|(port, fd)| (*port, fd.as_raw_fd()))
+                .unzip()
+        };
+        let mut message = serde_json::to_vec(&ports)?;
+        message.push(b'\n');
+        let mut sent = 0;
+        while sent < message.len() {
+            stream.writable().await?;
+            let fds: &[RawFd] = if sent == 0 { &fds } else { &[] };
+            match stream.send_with_fd(&message[sent..], fds) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(ports.len())
+    }
+}
+
+/// Binds `socket`, readable and writable by our user only, replacing what is at
+/// that path only if it is a socket nobody listens on anymore.
+async fn bind_handoff(socket: &str) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(socket) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(ProxyError::HandoffFailed(format!(
+                "{} exists and is not a socket",
+                socket
+            )));
+        }
+        Ok(_) => match UnixStream::connect(socket).await {
+            Ok(_) => {
+                return Err(ProxyError::HandoffFailed(format!(
+                    "{} is in use by another process",
+                    socket
+                )));
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(socket)?,
+            Err(e) => return Err(e.into()),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let server = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+    Ok(server)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn from_fds(fds: Vec<OwnedFd>) -> Result<HashMap<u16, std::net::TcpListener>> {
+    fds.into_iter()
+        .map(|fd| {
+            let listener = std::net::TcpListener::from(fd);
+            Ok((listener.local_addr()?.port(), listener))
+        })
+        .collect()
+}
+
+/// The number of sockets systemd passed to this process via socket activation,
+/// taken from `LISTEN_FDS` and `LISTEN_PID`, which are then cleared so child
+/// processes don't take the sockets for theirs. Changing the environment is
+/// unsound once other threads run, so this is called before the runtime starts.
+pub fn take_systemd_fds() -> RawFd {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if !for_us || count <= 0 {
+        return 0;
+    }
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    count
+}
+
+fn systemd_listeners(count: RawFd) -> Result<HashMap<u16, std::net::TcpListener>> {
+    // SAFETY: systemd passes ownership of fds 3..3+LISTEN_FDS to this process
+    let fds = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+    from_fds(fds)
+}
+
+async fn receive(socket: &str) -> Result<HashMap<u16, std::net::TcpListener>> {
+    tracing::info!("requesting listeners from running process on: {}", socket);
+    let mut stream = UnixStream::connect(socket).await?;
+    stream.write_all(HANDOFF_REQUEST).await?;
+    let listeners = receive_from(&stream).await?;
+    // the old process stops serving the socket before closing the connection
+    let _ = stream.read(&mut [0u8; 1]).await;
+    Ok(listeners)
+}
+
+async fn receive_from(stream: &UnixStream) -> Result<HashMap<u16, std::net::TcpListener>> {
+    let mut message = Vec::new();
+    let mut fds = Vec::new();
+    let mut bytes = [0u8; 4096];
+    let mut received = [0 as RawFd; MAX_HANDOFF_FDS];
+    while !message.ends_with(b"\n") {
+        stream.readable().await?;
+        match stream.recv_with_fd(&mut bytes, &mut received) {
+            Ok((0, _)) => return Err(ProxyError::HandoffFailed("connection closed".into())),
+            Ok((n, fd_count)) => {
+                message.extend_from_slice(&bytes[..n]);
+                // SAFETY: fds received over SCM_RIGHTS are newly allocated for this process
+                fds.extend(
+                    received[..fd_count]
+                        .iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) }),
+                );
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let ports: Vec<u16> = serde_json::from_slice(&message)?;
+    if ports.len() != fds.len() {
+        return Err(ProxyError::HandoffFailed(format!(
+            "expected {} listeners, received {}",
+            ports.len(),
+            fds.len()
+        )));
+    }
+    from_fds(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn hands_listeners_over_a_socketpair() -> Result<()> {
+        let listeners = Listeners::default();
+        let port = listeners.bind(0)?.local_addr()?.port();
+        let (ours, theirs) = UnixStream::pair()?;
+        let (sent, received) = tokio::join!(listeners.send(&ours), receive_from(&theirs));
+        assert_eq!(sent?, 1);
+        let listener = received?.remove(&port).expect("listener not handed over");
+        let _client = std::net::TcpStream::connect(("127.0.0.1", port))?;
+        assert!(listener.accept().is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_handoff_on_private_socket_only_once() -> Result<()> {
+        let socket =
+            std::env::temp_dir().join(format!("liteginx-handoff-{}.sock", std::process::id()));
+        let socket = socket.to_str().unwrap();
+        let listeners = Arc::new(Listeners::default());
+        listeners.bind(0)?;
+        let serving = tokio::spawn({
+            let listeners = Arc::clone(&listeners);
+            let socket = socket.to_string();
+            async move { listeners.serve_handoff(&socket).await }
+        });
+        while std::fs::metadata(socket).is_err() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            std::fs::metadata(socket)?.permissions().mode() & 0o777,
+            0o600
+        );
+        // a second process can't take over the socket of a running one
+        assert!(matches!(
+            bind_handoff(socket).await,
+            Err(ProxyError::HandoffFailed(e)) if e.contains("in use")
+        ));
+        assert_eq!(receive(socket).await?.len(), 1);
+        serving.await.unwrap()?;
+        // once nobody listens, the stale socket is replaced
+        assert!(bind_handoff(socket).await.is_ok());
+        std::fs::remove_file(socket)?;
+        Ok(())
+    }
+}
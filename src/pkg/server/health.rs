@@ -0,0 +1,211 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{Request, StatusCode, Uri};
+use tokio::{
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    pkg::{
+        server::{http2::scheme, proxy_protocol::ProxyAddrs, shutdown::Shutdown},
+        spec::routes::{HealthCheck, Route, TargetState, UpstreamTarget},
+    },
+    prelude::{ProxyError, Result},
+};
+
+const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+/// `HealthCheckResponse.ServingStatus.SERVING`
+const SERVING: u64 = 1;
+
+/// Runs the health checks of every target of `route` that has one until
+/// shutdown, taking targets out of rotation while they fail. Disabled targets
+/// are not checked.
+pub async fn watch(route: Arc<Route>, shutdown: Shutdown) {
+    let mut checks = JoinSet::new();
+    for target in &route.targets {
+        if let Some(ref check) = target.check {
+            checks.spawn(watch_target(
+                Arc::clone(&route),
+                target.clone(),
+                check.clone(),
+                shutdown.clone(),
+            ));
+        }
+    }
+    checks.join_all().await;
+}
+
+async fn watch_target(
+    route: Arc<Route>,
+    target: UpstreamTarget,
+    check: HealthCheck,
+    shutdown: Shutdown,
+) {
+    loop {
+        if target.rotation.get() == TargetState::Disabled {
+            tokio::select! {
+                _ = sleep(check.interval) => continue,
+                _ = shutdown.triggered() => break,
+            }
+        }
+        let healthy = match timeout(check.timeout, probe(&route, &target, &check.service)).await {
+            Ok(Ok(serving)) => serving,
+            Ok(Err(e)) => {
+                tracing::debug!(
+                    "health check of {}:{} failed: {:?}",
+                    &target.host,
+                    &target.port,
+                    e
+                );
+                false
+            }
+            Err(_) => false,
+        };
+        if target.health.set(healthy) {
+            match healthy {
+                true => tracing::info!("target {}:{} is healthy", &target.host, &target.port),
+                false => tracing::warn!("target {}:{} is unhealthy", &target.host, &target.port),
+            }
+        }
+        tokio::select! {
+            _ = sleep(check.interval) => {}
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
+
+/// Calls `grpc.health.v1.Health/Check` for `service` on `target`, over the same
+/// HTTP/2 connection requests to it use.
+async fn probe(route: &Route, target: &UpstreamTarget, service: &str) -> Result<bool> {
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let addrs = ProxyAddrs {
+        source: unspecified,
+        destination: unspecified,
+    };
+    let mut sender = route.pool.h2_sender(target, addrs, route.timeouts).await?;
+    let uri = Uri::builder()
+        .scheme(scheme(target))
+        .authority(format!("{}:{}", &target.host, &target.port))
+        .path_and_query(CHECK_PATH)
+        .build()?;
+    let request = Request::post(uri)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())?;
+    let (response, mut send) = sender.send_request(request, false)?;
+    send.send_data(encode_request(service), true)?;
+    let (parts, mut body) = response.await?.into_parts();
+    let mut message = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data?;
+        body.flow_control().release_capacity(data.len())?;
+        message.extend_from_slice(&data);
+    }
+    let trailers = body.trailers().await?.unwrap_or_default();
+    let status = trailers
+        .get("grpc-status")
+        .or(parts.headers.get("grpc-status"));
+    if parts.status != StatusCode::OK || status.is_none_or(|s| s != "0") {
+        return Err(ProxyError::UpstreamConnectionRefused(format!(
+            "health check answered {} with grpc-status {:?}",
+            parts.status, status
+        )));
+    }
+    Ok(decode_status(&message) == Some(SERVING))
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A length-prefixed `HealthCheckRequest { string service = 1; }`.
+fn encode_request(service: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    if !service.is_empty() {
+        message.put_u8(0x0a);
+        put_varint(&mut message, service.len() as u64);
+        message.put_slice(service.as_bytes());
+    }
+    let mut frame = BytesMut::with_capacity(5 + message.len());
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put(message);
+    frame.freeze()
+}
+
+/// The `status` of a length-prefixed, uncompressed `HealthCheckResponse`.
+fn decode_status(frame: &[u8]) -> Option<u64> {
+    let (&[0], rest) = frame.split_first_chunk::<1>()? else {
+        return None;
+    };
+    let (len, mut message) = rest.split_first_chunk::<4>()?;
+    if message.len() != u32::from_be_bytes(*len) as usize {
+        return None;
+    }
+    let mut status = 0;
+    while !message.is_empty() {
+        let key = get_varint(&mut message)?;
+        match (key >> 3, key & 7) {
+            (1, 0) => status = get_varint(&mut message)?,
+            (_, 0) => {
+                get_varint(&mut message)?;
+            }
+            (_, 1) => message = message.get(8..)?,
+            (_, 2) => {
+                let len = get_varint(&mut message)? as usize;
+                message = message.get(len..)?;
+            }
+            (_, 5) => message = message.get(4..)?,
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_check_request() {
+        assert_eq!(&encode_request("")[..], &[0, 0, 0, 0, 0]);
+        assert_eq!(
+            &encode_request("pkg.Svc")[..],
+            b"\x00\x00\x00\x00\x09\x0a\x07pkg.Svc"
+        );
+    }
+
+    #[test]
+    fn decodes_serving_status() {
+        assert_eq!(decode_status(&[0, 0, 0, 0, 2, 0x08, 0x01]), Some(SERVING));
+        assert_eq!(decode_status(&[0, 0, 0, 0, 2, 0x08, 0x02]), Some(2));
+        // an empty message is the default status, UNKNOWN
+        assert_eq!(decode_status(&[0, 0, 0, 0, 0]), Some(0));
+        // unknown fields are skipped
+        assert_eq!(
+            decode_status(&[0, 0, 0, 0, 6, 0x12, 0x02, b'o', b'k', 0x08, 0x01]),
+            Some(SERVING)
+        );
+        assert_eq!(decode_status(&[1, 0, 0, 0, 2, 0x08, 0x01]), None);
+        assert_eq!(decode_status(&[0, 0, 0, 0, 3, 0x08, 0x01]), None);
+    }
+}
@@ -1,58 +1,110 @@
+use http::StatusCode;
 use matchit::Router;
 use serde_json::json;
 
-use crate::pkg::{conf::settings, spec::routes::Endpoint, Result};
+use crate::{
+    pkg::{conf::settings, spec::routes::Endpoint},
+    prelude::{ProxyError, Result},
+};
 
-pub fn extract_path(body: &[u8]) -> &str {
-    let mut lines = body.split(|&b| b == b'\r' || b == b'\n');
-    if let Some(request_line) = lines.next() {
-        let mut parts = request_line.splitn(3, |&b| b == b' ');
-        parts.next();
-        if let Some(uri) = parts.next() {
-            let path = std::str::from_utf8(uri).unwrap_or("/");
-            return path.strip_prefix('/').unwrap_or(path);
-        }
+pub fn match_prefix<'a>(router: &'a Router<Endpoint>, path: &str) -> Option<&'a Endpoint> {
+    prefixes(path)
+        .iter()
+        .find_map(|prefix| router.at(prefix).ok())
+        .map(|m| m.value)
+}
+
+/// The paths `match_prefix` looks up for `path`, longest first.
+pub fn prefixes(path: &str) -> Vec<String> {
+    let mut parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let mut prefixes = vec![];
+    while !parts.is_empty() {
+        prefixes.push(format!("/{}", parts.join("/")));
+        parts.pop();
     }
-    ""
+    prefixes
 }
 
-pub fn match_prefix<'a>(router: &'a Router<Endpoint>, path: &str) -> Option<&'a Endpoint> {
-    let mut parts: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+/// The request target sent upstream for `target` once it matched `endpoint`.
+pub fn rewrite_path(endpoint: &Endpoint, target: &str) -> String {
+    match endpoint.rewrite {
+        Some(ref rewrite) => {
+            tracing::info!("rewriting path: {:?} to {:?}", target, rewrite);
+            rewrite.clone()
+        }
+        None => target.to_string(),
+    }
+}
 
-    while !parts.is_empty() {
-        let try_path = format!("/{}", parts.join("/"));
-        if let Ok(m) = router.at(&try_path) {
-            return Some(m.value);
+/// The status and detail a client is answered with when its request fails with
+/// `e`, or `None` if the connection should just be closed.
+pub fn error_status(e: &ProxyError) -> Option<(StatusCode, &'static str)> {
+    match e {
+        ProxyError::ClientHeaderTimeout => Some((StatusCode::REQUEST_TIMEOUT, "request timeout")),
+        ProxyError::MalformedHttp(_) => Some((StatusCode::BAD_REQUEST, "bad request")),
+        ProxyError::UpstreamResponseTimeout | ProxyError::UpstreamConnectTimeout => {
+            Some((StatusCode::GATEWAY_TIMEOUT, "upstream timed out"))
         }
-        parts.pop();
+        ProxyError::UpstreamConnectionRefused(_)
+        | ProxyError::UpStreamEndOfBytes
+        | ProxyError::Http2Error(_) => Some((StatusCode::BAD_GATEWAY, "upstream unavailable")),
+        ProxyError::ForwardDenied(_) => Some((StatusCode::FORBIDDEN, "destination not allowed")),
+        ProxyError::ClientNotAllowed(_) => Some((StatusCode::FORBIDDEN, "client not allowed")),
+        ProxyError::UpgradeUnsupported => {
+            Some((StatusCode::NOT_IMPLEMENTED, "upgrade not supported"))
+        }
+        ProxyError::NoHealthyTargets => {
+            Some((StatusCode::SERVICE_UNAVAILABLE, "no healthy upstream"))
+        }
+        ProxyError::TargetDisabled => Some((StatusCode::SERVICE_UNAVAILABLE, "upstream disabled")),
+        _ => None,
     }
-    None
 }
 
-pub fn rewrite_path(data: &[u8], search: Vec<u8>, replacement: Vec<u8>) -> Vec<u8> {
-    data.windows(search.len())
-        .enumerate()
-        .find(|(_, window)| *window == search)
-        .map(|(i, _)| {
-            let mut new_data = data.to_vec();
-            new_data.splice(i..i + search.len(), replacement.iter().copied());
-            new_data
-        })
-        .unwrap_or_else(|| data.to_vec())
+pub fn not_found_detail() -> String {
+    settings
+        .not_found_message
+        .clone()
+        .unwrap_or("not found".into())
+}
+
+pub fn json_detail(detail: &str) -> Result<String> {
+    Ok(serde_json::to_string(&json!({ "detail": detail }))?)
+}
+
+pub fn http_404_response(headers: &[(&str, &str)]) -> Result<String> {
+    http_json_response(StatusCode::NOT_FOUND, &not_found_detail(), headers)
+}
+
+pub fn http_error_response(e: &ProxyError, headers: &[(&str, &str)]) -> Result<Option<String>> {
+    error_status(e)
+        .map(|(status, detail)| http_json_response(status, detail, headers))
+        .transpose()
 }
 
-pub fn http_404_response() -> Result<String> {
-    let body = serde_json::to_string(&json!({
-        "detail": &settings.not_found_message.clone().unwrap_or("not found".into())
-    }))?;
+fn http_json_response(
+    status: StatusCode,
+    detail: &str,
+    headers: &[(&str, &str)],
+) -> Result<String> {
+    let body = json_detail(detail)?;
     let content_length = body.len();
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
     Ok(format!(
-        "HTTP/1.1 404 Not Found\r\n\
+        "HTTP/1.1 {} {}\r\n\
         Content-Type: application/json\r\n\
         Content-Length: {}\r\n\
+        {}\
         Connection: close\r\n\
         \r\n\
         {}",
-        content_length, body
+        status.as_str(),
+        status.canonical_reason().unwrap_or_default(),
+        content_length,
+        headers,
+        body
     ))
 }
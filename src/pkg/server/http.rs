@@ -0,0 +1,821 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{timeout, timeout_at, Instant},
+};
+
+use crate::{
+    pkg::conf::settings,
+    prelude::{ProxyError, Result},
+};
+
+const DEFAULT_MAX_HEAD_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_HEADERS: usize = 100;
+const READ_SIZE: usize = 16 * 1024;
+/// Largest chunk of a chunked body taken, well above what peers send in practice.
+const MAX_CHUNK_SIZE: u64 = 1 << 32;
+
+/// Headers that only describe a single hop and must not be forwarded as is.
+const HOP_BY_HOP: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: u8,
+    pub headers: Vec<Header>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<Header>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Longest request or response head read before giving up on it.
+fn max_head_size() -> usize {
+    settings.max_header_size.unwrap_or(DEFAULT_MAX_HEAD_SIZE)
+}
+
+/// Most header fields a request or response head may have.
+fn max_headers() -> usize {
+    settings.max_headers.unwrap_or(DEFAULT_MAX_HEADERS)
+}
+
+fn find<'a, 'b>(
+    headers: &'a [Header],
+    name: &'b str,
+) -> impl Iterator<Item = &'a str> + use<'a, 'b> {
+    headers
+        .iter()
+        .filter(move |h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+fn has_token(headers: &[Header], name: &str, token: &str) -> bool {
+    find(headers, name)
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+/// The protocol asked for or agreed on by a `Connection: Upgrade` message.
+fn upgrade(headers: &[Header]) -> Option<&str> {
+    match has_token(headers, "connection", "upgrade") {
+        true => find(headers, "upgrade").next(),
+        false => None,
+    }
+}
+
+/// How a message body is delimited. A request framed by both `Transfer-Encoding`
+/// and `Content-Length`, or by conflicting lengths, could be read differently by
+/// the target than by us, so it is refused rather than forwarded.
+fn body_framing(headers: &[Header], request: bool) -> Result<Option<Framing>> {
+    let codings: Vec<&str> = find(headers, "transfer-encoding")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+    let lengths: Vec<&str> = find(headers, "content-length")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    if let Some((last, rest)) = codings.split_last() {
+        if request && !lengths.is_empty() {
+            return Err(ProxyError::MalformedHttp(
+                "both transfer-encoding and content-length".into(),
+            ));
+        }
+        if rest.iter().any(|v| v.eq_ignore_ascii_case("chunked")) {
+            return Err(ProxyError::MalformedHttp("chunked applied twice".into()));
+        }
+        return match last.eq_ignore_ascii_case("chunked") {
+            true => Ok(Some(Framing::Chunked)),
+            false if request => Err(ProxyError::MalformedHttp(
+                "unsupported transfer-encoding".into(),
+            )),
+            false => Ok(Some(Framing::UntilClose)),
+        };
+    }
+    let Some((first, rest)) = lengths.split_first() else {
+        return Ok(None);
+    };
+    if rest.iter().any(|length| length != first) {
+        return Err(ProxyError::MalformedHttp(
+            "conflicting content-length".into(),
+        ));
+    }
+    // only digits, as parse would also take a sign
+    let invalid = || ProxyError::MalformedHttp("invalid content-length".into());
+    if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    first
+        .parse()
+        .map(|length| Some(Framing::Length(length)))
+        .map_err(|_| invalid())
+}
+
+fn keep_alive(version: u8, headers: &[Header]) -> bool {
+    if has_token(headers, "connection", "close") {
+        return false;
+    }
+    version >= 1 || has_token(headers, "connection", "keep-alive")
+}
+
+/// Removes hop-by-hop headers, including any named in `Connection`, and any
+/// `Content-Length` of a message framed by `Transfer-Encoding`.
+fn strip_hop_by_hop(headers: &mut Vec<Header>) {
+    if find(headers, "transfer-encoding").next().is_some() {
+        headers.retain(|h| !h.name.eq_ignore_ascii_case("content-length"));
+    }
+    let named: Vec<String> = find(headers, "connection")
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .collect();
+    headers.retain(|h| {
+        let name = h.name.to_ascii_lowercase();
+        !HOP_BY_HOP.contains(&name.as_str()) && !named.contains(&name)
+    });
+}
+
+fn encode_headers(out: &mut Vec<u8>, headers: &[Header]) {
+    for header in headers {
+        out.extend_from_slice(header.name.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(header.value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+fn set_header(headers: &mut Vec<Header>, name: &str, value: &str) {
+    headers.retain(|h| !h.name.eq_ignore_ascii_case(name));
+    headers.push(Header {
+        name: name.into(),
+        value: value.into(),
+    });
+}
+
+fn chunk_size(line: &[u8]) -> Result<u64> {
+    let size = std::str::from_utf8(line)
+        .ok()
+        .and_then(|l| l.trim_end().split(';').next())
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|s| u64::from_str_radix(s, 16).ok())
+        .ok_or(ProxyError::MalformedHttp("invalid chunk size".into()))?;
+    if size > MAX_CHUNK_SIZE {
+        return Err(ProxyError::MalformedHttp(format!(
+            "chunk of {} bytes over the {} byte limit",
+            size, MAX_CHUNK_SIZE
+        )));
+    }
+    Ok(size)
+}
+
+/// Encodes `data` as a single chunk of a chunked body.
+pub fn chunk(data: &[u8]) -> Vec<u8> {
+    let mut out = format!("{:x}\r\n", data.len()).into_bytes();
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// The zero-sized chunk ending a chunked body, followed by `trailers`.
+pub fn last_chunk(trailers: &[Header]) -> Vec<u8> {
+    let mut out = b"0\r\n".to_vec();
+    encode_headers(&mut out, trailers);
+    out
+}
+
+fn to_headers(parsed: &[httparse::Header]) -> Vec<Header> {
+    parsed
+        .iter()
+        .map(|h| Header {
+            name: h.name.to_string(),
+            value: String::from_utf8_lossy(h.value).into_owned(),
+        })
+        .collect()
+}
+
+impl RequestHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find(&self.headers, name).next()
+    }
+
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.headers, name, value);
+    }
+
+    pub fn push_header(&mut self, name: &str, value: &str) {
+        self.headers.push(Header {
+            name: name.into(),
+            value: value.into(),
+        });
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|h| !h.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers)
+    }
+
+    pub fn upgrade(&self) -> Option<&str> {
+        upgrade(&self.headers)
+    }
+
+    pub fn framing(&self) -> Result<Framing> {
+        Ok(body_framing(&self.headers, true)?.unwrap_or(Framing::Length(0)))
+    }
+
+    pub fn strip_hop_by_hop(&mut self) {
+        strip_hop_by_hop(&mut self.headers);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = format!(
+            "{} {} HTTP/1.{}\r\n",
+            self.method, self.target, self.version
+        )
+        .into_bytes();
+        encode_headers(&mut out, &self.headers);
+        out
+    }
+}
+
+impl ResponseHead {
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.headers, name, value);
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers)
+    }
+
+    pub fn upgrade(&self) -> Option<&str> {
+        upgrade(&self.headers)
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status)
+    }
+
+    /// How the body of this response is delimited, given the method of the request it answers.
+    pub fn framing(&self, method: &str) -> Result<Framing> {
+        if method.eq_ignore_ascii_case("HEAD")
+            || self.is_informational()
+            || self.status == 204
+            || self.status == 304
+        {
+            return Ok(Framing::Length(0));
+        }
+        Ok(body_framing(&self.headers, false)?.unwrap_or(Framing::UntilClose))
+    }
+
+    pub fn strip_hop_by_hop(&mut self) {
+        strip_hop_by_hop(&mut self.headers);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = format!(
+            "HTTP/1.{} {} {}\r\n",
+            self.version, self.status, self.reason
+        )
+        .into_bytes();
+        encode_headers(&mut out, &self.headers);
+        out
+    }
+}
+
+/// Progress through a message body read with `HttpConn::read_data`.
+#[derive(Debug)]
+pub struct BodyReader {
+    framing: Framing,
+    remaining: u64,
+    in_chunk: bool,
+    done: bool,
+    pub trailers: Vec<Header>,
+}
+
+impl BodyReader {
+    pub fn new(framing: Framing) -> Self {
+        let (remaining, done) = match framing {
+            Framing::Length(length) => (length, length == 0),
+            _ => (0, false),
+        };
+        Self {
+            framing,
+            remaining,
+            in_chunk: false,
+            done,
+            trailers: Vec::new(),
+        }
+    }
+}
+
+/// A stream carrying HTTP/1.x messages, with the bytes read past the end of the
+/// current head kept around for the body or the next message.
+#[derive(Debug)]
+pub struct HttpConn<S> {
+    pub stream: S,
+    buf: Vec<u8>,
+    read_timeout: Duration,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> HttpConn<S> {
+    pub fn new(stream: S, read_timeout: Duration) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            read_timeout,
+        }
+    }
+
+    /// Whether bytes beyond the last message were already read off the stream.
+    pub fn has_buffered(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Takes the bytes read past the last message, for when the stream stops
+    /// carrying HTTP.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    /// Reads until the stream is known to start with `prefix` or not, without
+    /// consuming anything. Timing out at `deadline` yields `ClientHeaderTimeout`.
+    pub async fn starts_with(&mut self, prefix: &[u8], deadline: Instant) -> Result<bool> {
+        while self.buf.len() < prefix.len() && prefix.starts_with(&self.buf) {
+            match self.fill(Some(deadline)).await {
+                Ok(0) => return Ok(false),
+                Ok(_) => {}
+                Err(ProxyError::IdleTimeout) => return Err(ProxyError::ClientHeaderTimeout),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.buf.starts_with(prefix))
+    }
+
+    async fn fill(&mut self, deadline: Option<Instant>) -> Result<usize> {
+        let mut chunk = [0u8; READ_SIZE];
+        let read = self.stream.read(&mut chunk);
+        let n = match deadline {
+            Some(deadline) => timeout_at(deadline, read).await,
+            None => timeout(self.read_timeout, read).await,
+        }
+        .map_err(|_| ProxyError::IdleTimeout)??;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// Reads the next request head, or `None` if the peer closed the connection
+    /// before sending anything. Timing out at `deadline` yields `ClientHeaderTimeout`.
+    pub async fn read_request(&mut self, deadline: Instant) -> Result<Option<RequestHead>> {
+        loop {
+            let mut headers = vec![httparse::EMPTY_HEADER; max_headers()];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(&self.buf) {
+                Ok(httparse::Status::Complete(n)) => {
+                    let head = RequestHead {
+                        method: req.method.unwrap_or_default().to_string(),
+                        target: req.path.unwrap_or_default().to_string(),
+                        version: req.version.unwrap_or(1),
+                        headers: to_headers(req.headers),
+                    };
+                    self.buf.drain(..n);
+                    return Ok(Some(head));
+                }
+                Ok(httparse::Status::Partial) => {}
+                Err(e) => return Err(ProxyError::MalformedHttp(e.to_string())),
+            }
+            if self.buf.len() > max_head_size() {
+                return Err(ProxyError::MalformedHttp("request head too large".into()));
+            }
+            let n = match self.fill(Some(deadline)).await {
+                Err(ProxyError::IdleTimeout) => return Err(ProxyError::ClientHeaderTimeout),
+                r => r?,
+            };
+            if n == 0 {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(ProxyError::DownStreamEndOfBytes),
+                };
+            }
+        }
+    }
+
+    /// Reads the next response head. Timing out at `deadline` yields `UpstreamResponseTimeout`.
+    pub async fn read_response(&mut self, deadline: Instant) -> Result<ResponseHead> {
+        loop {
+            let mut headers = vec![httparse::EMPTY_HEADER; max_headers()];
+            let mut res = httparse::Response::new(&mut headers);
+            match res.parse(&self.buf) {
+                Ok(httparse::Status::Complete(n)) => {
+                    let head = ResponseHead {
+                        version: res.version.unwrap_or(1),
+                        status: res.code.unwrap_or_default(),
+                        reason: res.reason.unwrap_or_default().to_string(),
+                        headers: to_headers(res.headers),
+                    };
+                    self.buf.drain(..n);
+                    return Ok(head);
+                }
+                Ok(httparse::Status::Partial) => {}
+                Err(e) => return Err(ProxyError::MalformedHttp(e.to_string())),
+            }
+            if self.buf.len() > max_head_size() {
+                return Err(ProxyError::MalformedHttp("response head too large".into()));
+            }
+            let n = match self.fill(Some(deadline)).await {
+                Err(ProxyError::IdleTimeout) => return Err(ProxyError::UpstreamResponseTimeout),
+                r => r?,
+            };
+            if n == 0 {
+                return Err(ProxyError::UpStreamEndOfBytes);
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                return Ok(self.buf.drain(..end + 2).collect());
+            }
+            if self.buf.len() > max_head_size() {
+                return Err(ProxyError::MalformedHttp("chunk line too long".into()));
+            }
+            if self.fill(None).await? == 0 {
+                return Err(ProxyError::MalformedHttp("truncated chunked body".into()));
+            }
+        }
+    }
+
+    async fn copy_exact<W>(&mut self, mut remaining: u64, to: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        while remaining > 0 {
+            if self.buf.is_empty() && self.fill(None).await? == 0 {
+                return Err(ProxyError::MalformedHttp("truncated body".into()));
+            }
+            let n = self.buf.len().min(remaining as usize);
+            to.write_all(&self.buf[..n]).await?;
+            self.buf.drain(..n);
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies a message body delimited by `framing` to `to`, unchanged, and
+    /// returns the number of bytes copied.
+    pub async fn copy_body<W>(&mut self, framing: Framing, to: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let copied = match framing {
+            Framing::Length(length) => {
+                self.copy_exact(length, to).await?;
+                length
+            }
+            Framing::Chunked => {
+                let mut copied = 0;
+                loop {
+                    let line = self.read_line().await?;
+                    to.write_all(&line).await?;
+                    copied += line.len() as u64;
+                    let size = chunk_size(&line)?;
+                    if size == 0 {
+                        // trailers, terminated by an empty line
+                        loop {
+                            let line = self.read_line().await?;
+                            to.write_all(&line).await?;
+                            copied += line.len() as u64;
+                            if line == b"\r\n" {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    // the chunk data and the CRLF ending it
+                    let data = size
+                        .checked_add(2)
+                        .ok_or(ProxyError::MalformedHttp("invalid chunk size".into()))?;
+                    self.copy_exact(data, to).await?;
+                    copied += data;
+                }
+                copied
+            }
+            Framing::UntilClose => {
+                let mut copied = 0;
+                loop {
+                    if self.buf.is_empty() && self.fill(None).await? == 0 {
+                        break;
+                    }
+                    to.write_all(&self.buf).await?;
+                    copied += self.buf.len() as u64;
+                    self.buf.clear();
+                }
+                copied
+            }
+        };
+        to.flush().await?;
+        Ok(copied)
+    }
+
+    /// Reads the next piece of a message body with its transfer coding removed,
+    /// or `None` once it is complete. Trailers of a chunked body end up in
+    /// `body.trailers`.
+    pub async fn read_data(&mut self, body: &mut BodyReader) -> Result<Option<Vec<u8>>> {
+        if body.done {
+            return Ok(None);
+        }
+        match body.framing {
+            Framing::UntilClose => {
+                if self.buf.is_empty() && self.fill(None).await? == 0 {
+                    body.done = true;
+                    return Ok(None);
+                }
+                return Ok(Some(std::mem::take(&mut self.buf)));
+            }
+            Framing::Chunked if body.remaining == 0 => {
+                if body.in_chunk && self.read_line().await? != b"\r\n" {
+                    return Err(ProxyError::MalformedHttp("invalid chunk terminator".into()));
+                }
+                let size = chunk_size(&self.read_line().await?)?;
+                if size == 0 {
+                    loop {
+                        let line = self.read_line().await?;
+                        if line == b"\r\n" {
+                            break;
+                        }
+                        let line = String::from_utf8_lossy(&line);
+                        if let Some((name, value)) = line.trim_end().split_once(':') {
+                            body.trailers.push(Header {
+                                name: name.trim().into(),
+                                value: value.trim().into(),
+                            });
+                        }
+                    }
+                    body.done = true;
+                    return Ok(None);
+                }
+                body.remaining = size;
+                body.in_chunk = true;
+            }
+            _ => {}
+        }
+        if self.buf.is_empty() && self.fill(None).await? == 0 {
+            return Err(ProxyError::MalformedHttp("truncated body".into()));
+        }
+        let n = self.buf.len().min(body.remaining as usize);
+        body.remaining -= n as u64;
+        body.done = matches!(body.framing, Framing::Length(_)) && body.remaining == 0;
+        Ok(Some(self.buf.drain(..n).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(data: &[u8]) -> HttpConn<tokio::io::DuplexStream> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let data = data.to_vec();
+        tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            // keep the peer open until the test is done reading
+            let mut sink = Vec::new();
+            let _ = client.read_to_end(&mut sink).await;
+        });
+        HttpConn::new(server, Duration::from_secs(1))
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(1)
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests() -> Result<()> {
+        let mut conn = conn(
+            b"POST /one HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET /two HTTP/1.1\r\nHost: a\r\n\r\n",
+        );
+        let head = conn.read_request(deadline()).await?.unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.target, "/one");
+        assert_eq!(head.framing()?, Framing::Length(5));
+        let mut body = Vec::new();
+        assert_eq!(conn.copy_body(head.framing()?, &mut body).await?, 5);
+        assert_eq!(body, b"hello");
+
+        let head = conn.read_request(deadline()).await?.unwrap();
+        assert_eq!(head.target, "/two");
+        assert_eq!(head.framing()?, Framing::Length(0));
+        assert!(conn.read_request(deadline()).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_upgrade_handshake() -> Result<()> {
+        let mut ws = conn(
+            b"GET /ws HTTP/1.1\r\nHost: a\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n\x81\x02hi",
+        );
+        let mut head = ws.read_request(deadline()).await?.unwrap();
+        assert_eq!(head.upgrade(), Some("websocket"));
+        head.strip_hop_by_hop();
+        assert!(head.upgrade().is_none());
+        assert_eq!(ws.take_buffered(), b"\x81\x02hi");
+        assert!(!ws.has_buffered());
+
+        let head = conn(b"GET / HTTP/1.1\r\nUpgrade: h2c\r\n\r\n")
+            .read_request(deadline())
+            .await?
+            .unwrap();
+        assert!(head.upgrade().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copies_chunked_body_with_trailers() -> Result<()> {
+        let body = b"4\r\nwiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let mut data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        data.extend_from_slice(body);
+        data.extend_from_slice(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let mut conn = conn(&data);
+        let head = conn.read_response(deadline()).await?;
+        assert_eq!(head.status, 200);
+        assert_eq!(head.framing("GET")?, Framing::Chunked);
+        let mut copied = Vec::new();
+        conn.copy_body(Framing::Chunked, &mut copied).await?;
+        assert_eq!(copied, body);
+        let head = conn.read_response(deadline()).await?;
+        assert_eq!(head.status, 204);
+        assert_eq!(head.framing("GET")?, Framing::Length(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_chunks() {
+        for size in ["ffffffffffffffff", "100000001", "+5", "0x5", ""] {
+            let mut conn = conn(format!("{}\r\nhello\r\n0\r\n\r\n", size).as_bytes());
+            let copied = conn.copy_body(Framing::Chunked, &mut Vec::new()).await;
+            assert!(
+                matches!(copied, Err(ProxyError::MalformedHttp(_))),
+                "{:?} copied",
+                size
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn response_framing() -> Result<()> {
+        let mut conn = conn(b"HTTP/1.0 200 OK\r\nServer: x\r\n\r\nuntil close");
+        let head = conn.read_response(deadline()).await?;
+        assert!(!head.keep_alive());
+        assert_eq!(head.framing("HEAD")?, Framing::Length(0));
+        assert_eq!(head.framing("GET")?, Framing::UntilClose);
+        let mut copied = Vec::new();
+        conn.copy_body(Framing::UntilClose, &mut copied).await?;
+        assert_eq!(copied, b"until close");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_body() -> Result<()> {
+        let mut conn = conn(b"4\r\nwiki\r\n5\r\npedia\r\n0\r\nGrpc-Status: 0\r\n\r\nnext");
+        let mut body = BodyReader::new(Framing::Chunked);
+        let mut data = Vec::new();
+        while let Some(chunk) = conn.read_data(&mut body).await? {
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"wikipedia");
+        assert_eq!(body.trailers[0].name, "Grpc-Status");
+        assert_eq!(body.trailers[0].value, "0");
+        assert!(conn.starts_with(b"next", deadline()).await?);
+        assert_eq!(
+            [chunk(b"wiki"), last_chunk(&body.trailers)].concat(),
+            b"4\r\nwiki\r\n0\r\nGrpc-Status: 0\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    fn request(headers: &[&str]) -> RequestHead {
+        RequestHead {
+            method: "GET".into(),
+            target: "/".into(),
+            version: 1,
+            headers: headers
+                .iter()
+                .map(|h| {
+                    let (name, value) = h.split_once(": ").unwrap();
+                    Header {
+                        name: name.into(),
+                        value: value.into(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut head = request(&[
+            "Host: a",
+            "Connection: close, X-Hop",
+            "X-Hop: 1",
+            "Keep-Alive: 5",
+        ]);
+        assert!(!head.keep_alive());
+        head.strip_hop_by_hop();
+        assert_eq!(head.encode(), b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    }
+
+    #[test]
+    fn rejects_ambiguous_request_framing() {
+        for headers in [
+            &["Transfer-Encoding: chunked", "Content-Length: 5"][..],
+            &["Content-Length: 5", "Transfer-Encoding: chunked"],
+            &["Transfer-Encoding: chunked, identity"],
+            &["Transfer-Encoding: chunked", "Transfer-Encoding: chunked"],
+            &["Content-Length: 5", "Content-Length: 50"],
+            &["Content-Length: 5, 50"],
+            &["Content-Length: -5"],
+            &["Content-Length: +5"],
+            &["Content-Length: +5, +5"],
+            &["Content-Length: 5, +5"],
+            &["Content-Length: 5, 05"],
+            &["Content-Length: 0x5"],
+            &["Content-Length: "],
+            &["Content-Length: 99999999999999999999"],
+        ] {
+            assert!(
+                matches!(
+                    request(headers).framing(),
+                    Err(ProxyError::MalformedHttp(_))
+                ),
+                "{:?}",
+                headers
+            );
+        }
+        let head = request(&["Transfer-Encoding: gzip, chunked"]);
+        assert_eq!(head.framing().unwrap(), Framing::Chunked);
+        let head = request(&["Content-Length: 5", "Content-Length: 5"]);
+        assert_eq!(head.framing().unwrap(), Framing::Length(5));
+        let head = request(&["Content-Length: 5, 5"]);
+        assert_eq!(head.framing().unwrap(), Framing::Length(5));
+    }
+
+    #[tokio::test]
+    async fn drops_content_length_of_chunked_response() -> Result<()> {
+        let mut chunked = conn(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        );
+        let mut head = chunked.read_response(deadline()).await?;
+        assert_eq!(head.framing("GET")?, Framing::Chunked);
+        head.strip_hop_by_hop();
+        assert_eq!(
+            head.encode(),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+
+        let head = conn(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n")
+            .read_response(deadline())
+            .await?;
+        assert_eq!(head.framing("GET")?, Framing::UntilClose);
+        Ok(())
+    }
+}
@@ -0,0 +1,675 @@
+use std::{
+    future::poll_fn,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use h2::{server::SendResponse, Reason, RecvStream, SendStream};
+use http::{
+    request::Parts, HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    task::JoinSet,
+    time::{sleep, timeout, Instant},
+};
+use tracing::Instrument;
+
+use crate::{
+    pkg::{
+        access_log,
+        server::{
+            downstream::ListenDownstream,
+            helpers::{error_status, json_detail, match_prefix, not_found_detail, rewrite_path},
+            http::{
+                chunk, last_chunk, BodyReader, Framing, Header, HttpConn, RequestHead, ResponseHead,
+            },
+            metrics::METRICS,
+            proxy_protocol::ProxyAddrs,
+            shutdown::Shutdown,
+            tls::{client_name, ClientCert},
+            upstream::unless_disabled,
+        },
+        spec::routes::{Route, UpstreamTarget},
+        telemetry::{self, Headers},
+    },
+    prelude::{ProxyError, Result},
+};
+
+/// What an HTTP/2 client sends first when it knows the server speaks h2c.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that are specific to an HTTP/1.x connection and not allowed in HTTP/2,
+/// plus `host` which HTTP/2 carries as `:authority`.
+const CONNECTION_SPECIFIC: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+];
+
+/// Replays bytes already read off `inner` before reading from it again, so a
+/// connection can be handed to the HTTP/2 server after sniffing its preface.
+pub struct Rewind<S> {
+    buffered: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(inner: S, buffered: Vec<u8>) -> Self {
+        Self { buffered, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.buffered.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = buf.remaining().min(self.buffered.len());
+        buf.put_slice(&self.buffered[..n]);
+        self.buffered.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn is_connection_specific(name: &str) -> bool {
+    CONNECTION_SPECIFIC
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+fn to_header_map(headers: &[Header]) -> HeaderMap {
+    headers
+        .iter()
+        .filter(|h| !is_connection_specific(&h.name))
+        .filter_map(|h| {
+            Some((
+                HeaderName::from_bytes(h.name.as_bytes()).ok()?,
+                HeaderValue::from_str(&h.value).ok()?,
+            ))
+        })
+        .fold(HeaderMap::new(), |mut map, (name, value)| {
+            map.append(name, value);
+            map
+        })
+}
+
+fn to_headers(map: &HeaderMap) -> Vec<Header> {
+    map.iter()
+        .map(|(name, value)| Header {
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        })
+        .collect()
+}
+
+/// Drops connection-specific headers, keeping `te` only for `trailers` as HTTP/2 requires.
+fn strip_connection_specific(headers: &mut HeaderMap) {
+    for name in CONNECTION_SPECIFIC {
+        headers.remove(name);
+    }
+    if headers.get("te").is_some_and(|te| te != "trailers") {
+        headers.remove("te");
+    }
+}
+
+/// Sends `data` as the peer's flow control window allows.
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = poll_fn(|cx| send.poll_capacity(cx))
+            .await
+            .ok_or(ProxyError::StreamClosed)??;
+        if capacity > 0 {
+            send.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+    }
+    Ok(())
+}
+
+fn end_stream(send: &mut SendStream<Bytes>, trailers: Option<HeaderMap>) -> Result<()> {
+    match trailers {
+        Some(trailers) => send.send_trailers(trailers)?,
+        None => send.send_data(Bytes::new(), true)?,
+    }
+    Ok(())
+}
+
+/// Copies an HTTP/2 body, trailers included, from one stream to another,
+/// passing the size of each chunk to `tally`.
+async fn pipe(mut body: RecvStream, mut send: SendStream<Bytes>, tally: fn(usize)) -> Result<()> {
+    while let Some(data) = body.data().await {
+        let data = data?;
+        body.flow_control().release_capacity(data.len())?;
+        tally(data.len());
+        send_data(&mut send, data).await?;
+    }
+    end_stream(&mut send, body.trailers().await?)
+}
+
+fn send_json(
+    respond: &mut SendResponse<Bytes>,
+    status: StatusCode,
+    detail: &str,
+    echo: &HeaderMap,
+) -> Result<()> {
+    let body = Bytes::from(json_detail(detail)?);
+    telemetry::record_sent(body.len());
+    let mut response = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(())?;
+    response.headers_mut().extend(echo.clone());
+    respond
+        .send_response(response, false)?
+        .send_data(body, true)?;
+    Ok(())
+}
+
+fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// The gRPC status for an error liteginx answers itself, following gRPC's
+/// mapping of HTTP statuses with timeouts reported as `DEADLINE_EXCEEDED`.
+fn grpc_status(status: StatusCode) -> u16 {
+    match status {
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => 4,
+        StatusCode::NOT_FOUND => 12,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => 14,
+        _ => 13,
+    }
+}
+
+/// Percent-encodes `message` as the `grpc-message` header requires.
+fn grpc_message(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Answers a gRPC call with a trailers-only response carrying the error.
+fn send_grpc_error(
+    respond: &mut SendResponse<Bytes>,
+    status: StatusCode,
+    detail: &str,
+    echo: &HeaderMap,
+) -> Result<()> {
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/grpc")
+        .header("grpc-status", grpc_status(status))
+        .header("grpc-message", grpc_message(detail))
+        .body(())?;
+    response.headers_mut().extend(echo.clone());
+    respond.send_response(response, true)?;
+    Ok(())
+}
+
+fn send_error(
+    respond: &mut SendResponse<Bytes>,
+    grpc: bool,
+    status: StatusCode,
+    detail: &str,
+    echo: &HeaderMap,
+) -> Result<()> {
+    // gRPC errors go out in the trailers of a 200
+    telemetry::record_status(if grpc { 200 } else { status.as_u16() });
+    match grpc {
+        true => send_grpc_error(respond, status, detail, echo),
+        false => send_json(respond, status, detail, echo),
+    }
+}
+
+pub fn scheme(target: &UpstreamTarget) -> &'static str {
+    match target.tls {
+        Some(_) => "https",
+        None => "http",
+    }
+}
+
+/// Serves an HTTP/2 connection, handling each stream concurrently up to the
+/// route's `max_concurrent_streams`. On shutdown or once idle, the client is
+/// sent a GOAWAY and in flight streams are left to finish.
+pub async fn serve<S>(
+    route: Arc<Route>,
+    stream: S,
+    addrs: ProxyAddrs,
+    cert: Option<Arc<ClientCert>>,
+    shutdown: &Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(route.http2.max_concurrent_streams)
+        .handshake::<_, Bytes>(stream);
+    let mut conn = timeout(route.timeouts.client_header, handshake)
+        .await
+        .map_err(|_| ProxyError::ClientHeaderTimeout)??;
+    tracing::debug!("serving http2 connection from {}", &addrs.source);
+    let mut streams = JoinSet::new();
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            next = conn.accept() => match next {
+                Some(Ok((mut request, respond))) => {
+                    let header = route.request_id.header.as_str();
+                    let id = route
+                        .request_id
+                        .resolve(request.headers().get(header).and_then(|v| v.to_str().ok()));
+                    if let (Ok(name), Ok(value)) =
+                        (HeaderName::try_from(header), HeaderValue::try_from(&id))
+                    {
+                        request.headers_mut().insert(name, value);
+                    }
+                    let parent = telemetry::extract(&Headers(request.headers_mut()));
+                    let span = telemetry::request_span(
+                        route.listen,
+                        request.method().as_str(),
+                        request.uri().path(),
+                        &id,
+                        parent,
+                    );
+                    let route = Arc::clone(&route);
+                    streams.spawn(
+                        respond_to(route, request, respond, addrs, cert.clone(), id)
+                            .instrument(span),
+                    );
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            _ = shutdown.triggered(), if !closing => {
+                conn.graceful_shutdown();
+                closing = true;
+            }
+            _ = sleep(route.timeouts.idle), if streams.is_empty() && !closing => {
+                tracing::debug!("http2 connection idle, closing");
+                conn.graceful_shutdown();
+                closing = true;
+            }
+        }
+    }
+    streams.join_all().await;
+    Ok(())
+}
+
+async fn respond_to(
+    route: Arc<Route>,
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    addrs: ProxyAddrs,
+    cert: Option<Arc<ClientCert>>,
+    request_id: String,
+) {
+    let started = Instant::now();
+    let client = addrs.source;
+    let echo = route.request_id.echoed(request.headers());
+    let mut record = telemetry::recording(async {
+        let grpc = is_grpc(request.headers());
+        let cert = cert.as_deref();
+        let r = forward(&route, request, &mut respond, addrs, cert, grpc, &echo).await;
+        tracing::debug!("http2 stream closed: {:?}", &r);
+        let Err(e) = r else {
+            return telemetry::take_record();
+        };
+        // answering fails if the response head already went out, reset the stream then
+        let answered = error_status(&e).is_some_and(|(status, detail)| {
+            send_error(&mut respond, grpc, status, detail, &echo).is_ok()
+        });
+        if !answered {
+            respond.send_reset(Reason::INTERNAL_ERROR);
+        }
+        telemetry::take_record()
+    })
+    .await;
+    record.request_id = Some(request_id);
+    METRICS.observe_request(route.listen, &record, started.elapsed());
+    access_log::log(route.listen, client, &record, started.elapsed());
+}
+
+async fn forward(
+    route: &Route,
+    request: Request<RecvStream>,
+    respond: &mut SendResponse<Bytes>,
+    addrs: ProxyAddrs,
+    cert: Option<&ClientCert>,
+    grpc: bool,
+    echo: &HeaderMap,
+) -> Result<()> {
+    let (mut parts, body) = request.into_parts();
+    let path = parts.uri.path();
+    telemetry::record_request(
+        parts.method.as_str(),
+        parts.uri.path_and_query().map_or(path, |p| p.as_str()),
+        "HTTP/2.0",
+        parts.uri.authority().map(|a| a.as_str()),
+        &Headers(&mut parts.headers),
+    );
+    let Some(endpoint) = route
+        .endpoints
+        .as_ref()
+        .and_then(|router| match_prefix(router, path))
+    else {
+        tracing::warn!("path {} not found", path);
+        return send_error(
+            respond,
+            grpc,
+            StatusCode::NOT_FOUND,
+            &not_found_detail(),
+            echo,
+        );
+    };
+    telemetry::record_route(parts.method.as_str(), &endpoint.path);
+    if !endpoint.admits(cert) {
+        return Err(ProxyError::ClientNotAllowed(client_name(cert)));
+    }
+    let target_path = rewrite_path(
+        endpoint,
+        parts.uri.path_and_query().map_or(path, |p| p.as_str()),
+    );
+    telemetry::record_rewrite(&target_path);
+    let authority = parts.uri.authority().map(|a| a.to_string()).or_else(|| {
+        parts
+            .headers
+            .get("host")
+            .and_then(|h| h.to_str().ok())
+            .map(String::from)
+    });
+    strip_connection_specific(&mut parts.headers);
+    parts.headers.append(
+        "x-forwarded-for",
+        HeaderValue::try_from(addrs.source.ip().to_string()).map_err(http::Error::from)?,
+    );
+    let header = HeaderName::try_from(route.client_header()).map_err(http::Error::from)?;
+    parts.headers.remove(&header);
+    if let Some(cert) = cert {
+        parts.headers.insert(
+            header,
+            HeaderValue::try_from(cert.header_value()).map_err(http::Error::from)?,
+        );
+    }
+    let target = route.target()?;
+    telemetry::record_target(target);
+    let _relaying = METRICS.relaying(target);
+    telemetry::inject(&mut Headers(&mut parts.headers));
+    let authority = authority.unwrap_or(format!("{}:{}", &target.host, &target.port));
+    if target.http2 {
+        parts.uri = Uri::builder()
+            .scheme(scheme(target))
+            .authority(authority)
+            .path_and_query(target_path)
+            .build()?;
+        return unless_disabled(
+            target,
+            to_h2(route, target, parts, body, respond, addrs, echo),
+        )
+        .await;
+    }
+    let mut head = RequestHead {
+        method: parts.method.to_string(),
+        target: target_path,
+        version: 1,
+        headers: to_headers(&parts.headers),
+    };
+    head.set_header("Host", &authority);
+    unless_disabled(
+        target,
+        to_h1(route, target, head, body, respond, addrs, echo),
+    )
+    .await
+}
+
+/// Forwards an HTTP/2 stream to an HTTP/2 target, with both bodies streamed at
+/// the same time so bidirectional streaming calls work.
+async fn to_h2(
+    route: &Route,
+    target: &UpstreamTarget,
+    parts: Parts,
+    body: RecvStream,
+    respond: &mut SendResponse<Bytes>,
+    addrs: ProxyAddrs,
+    echo: &HeaderMap,
+) -> Result<()> {
+    let mut sender = route.pool.h2_sender(target, addrs, route.timeouts).await?;
+    let end = body.is_end_stream();
+    let (response, upload) = sender.send_request(Request::from_parts(parts, ()), end)?;
+    let upload = async {
+        match end {
+            true => Ok(()),
+            false => pipe(body, upload, telemetry::record_received).await,
+        }
+    };
+    let download = async {
+        let response = timeout(route.timeouts.upstream_response, response)
+            .await
+            .map_err(|_| ProxyError::UpstreamResponseTimeout)??;
+        let (mut parts, body) = response.into_parts();
+        strip_connection_specific(&mut parts.headers);
+        parts.headers.extend(echo.clone());
+        telemetry::record_status(parts.status.as_u16());
+        let end = body.is_end_stream();
+        let download = respond.send_response(Response::from_parts(parts, ()), end)?;
+        match end {
+            true => Ok(()),
+            false => pipe(body, download, telemetry::record_sent).await,
+        }
+    };
+    tokio::pin!(upload, download);
+    let mut uploading = true;
+    loop {
+        tokio::select! {
+            r = &mut upload, if uploading => {
+                r?;
+                uploading = false;
+            }
+            r = &mut download => return r,
+        }
+    }
+}
+
+/// Forwards an HTTP/2 stream to an HTTP/1.1 target, sending the request body
+/// chunked unless the client declared its length.
+async fn to_h1(
+    route: &Route,
+    target: &UpstreamTarget,
+    mut head: RequestHead,
+    mut body: RecvStream,
+    respond: &mut SendResponse<Bytes>,
+    addrs: ProxyAddrs,
+    echo: &HeaderMap,
+) -> Result<()> {
+    let framing = match head.header("content-length") {
+        _ if body.is_end_stream() => Framing::Length(0),
+        Some(_) => head.framing()?,
+        None => {
+            head.set_header("Transfer-Encoding", "chunked");
+            Framing::Chunked
+        }
+    };
+    let mut conn = route.checkout(target, addrs).await?;
+    let upstream = &mut conn.conn;
+    upstream.stream.write_all(&head.encode()).await?;
+    if framing != Framing::Length(0) {
+        while let Some(data) = body.data().await {
+            let data = data?;
+            body.flow_control().release_capacity(data.len())?;
+            telemetry::record_received(data.len());
+            match framing {
+                Framing::Chunked => upstream.stream.write_all(&chunk(&data)).await?,
+                _ => upstream.stream.write_all(&data).await?,
+            }
+        }
+    }
+    if framing == Framing::Chunked {
+        let trailers = body.trailers().await?;
+        let trailers = trailers.as_ref().map(to_headers).unwrap_or_default();
+        upstream.stream.write_all(&last_chunk(&trailers)).await?;
+    }
+    upstream.stream.flush().await?;
+    tracing::debug!("forwarded http2 request to upstream target");
+
+    let deadline = Instant::now() + route.timeouts.upstream_response;
+    let mut response = upstream.read_response(deadline).await?;
+    while response.is_informational() {
+        response = upstream.read_response(deadline).await?;
+    }
+    let framing = response.framing(&head.method)?;
+    let keep_alive = response.keep_alive() && framing != Framing::UntilClose;
+    telemetry::record_status(response.status);
+    let mut builder = Response::builder().status(response.status);
+    if let Some(headers) = builder.headers_mut() {
+        *headers = to_header_map(&response.headers);
+        headers.extend(echo.clone());
+    }
+    let end = framing == Framing::Length(0);
+    let mut download = respond.send_response(builder.body(())?, end)?;
+    if !end {
+        let mut reader = BodyReader::new(framing);
+        while let Some(data) = upstream.read_data(&mut reader).await? {
+            telemetry::record_sent(data.len());
+            send_data(&mut download, data.into()).await?;
+        }
+        let trailers = (!reader.trailers.is_empty()).then(|| to_header_map(&reader.trailers));
+        end_stream(&mut download, trailers)?;
+    }
+    if keep_alive {
+        route.pool.release(conn);
+    }
+    Ok(())
+}
+
+/// Forwards a request read off an HTTP/1.x client to an HTTP/2 target, returning
+/// whether the client connection can carry another request. Response bodies of
+/// unknown length go back chunked, or delimited by closing for HTTP/1.0 clients.
+pub async fn from_h1<S>(
+    route: &Route,
+    target: &UpstreamTarget,
+    client: &mut HttpConn<S>,
+    head: &RequestHead,
+    framing: Framing,
+    addrs: ProxyAddrs,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut sender = route.pool.h2_sender(target, addrs, route.timeouts).await?;
+    let authority = head
+        .header("host")
+        .map(String::from)
+        .unwrap_or(format!("{}:{}", &target.host, &target.port));
+    let mut request = Request::builder()
+        .method(head.method.as_str())
+        .uri(
+            Uri::builder()
+                .scheme(scheme(target))
+                .authority(authority)
+                .path_and_query(head.target.as_str())
+                .build()?,
+        )
+        .body(())?;
+    *request.headers_mut() = to_header_map(&head.headers);
+    let end = framing == Framing::Length(0);
+    let (response, mut upload) = sender.send_request(request, end)?;
+    if !end {
+        let mut reader = BodyReader::new(framing);
+        while let Some(data) = client.read_data(&mut reader).await? {
+            send_data(&mut upload, data.into()).await?;
+        }
+        let trailers = (!reader.trailers.is_empty()).then(|| to_header_map(&reader.trailers));
+        end_stream(&mut upload, trailers)?;
+    }
+    tracing::debug!("forwarded request to http2 upstream target");
+
+    let response = timeout(route.timeouts.upstream_response, response)
+        .await
+        .map_err(|_| ProxyError::UpstreamResponseTimeout)??;
+    let (parts, mut body) = response.into_parts();
+    telemetry::record_status(parts.status.as_u16());
+    let mut response = ResponseHead {
+        version: 1,
+        status: parts.status.as_u16(),
+        reason: parts
+            .status
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_string(),
+        headers: to_headers(&parts.headers),
+    };
+    route.request_id.echo(head, &mut response);
+    let mut keep_alive = head.keep_alive();
+    let framing = match response.framing(&head.method)? {
+        Framing::UntilClose if body.is_end_stream() => {
+            response.set_header("Content-Length", "0");
+            Framing::Length(0)
+        }
+        Framing::UntilClose if head.version >= 1 => {
+            response.set_header("Transfer-Encoding", "chunked");
+            Framing::Chunked
+        }
+        Framing::UntilClose => {
+            keep_alive = false;
+            Framing::UntilClose
+        }
+        framing => framing,
+    };
+    if !keep_alive {
+        response.set_header("Connection", "close");
+    }
+    client.stream.write_all(&response.encode()).await?;
+    if framing != Framing::Length(0) {
+        while let Some(data) = body.data().await {
+            let data = data?;
+            body.flow_control().release_capacity(data.len())?;
+            match framing {
+                Framing::Chunked => client.stream.write_all(&chunk(&data)).await?,
+                _ => client.stream.write_all(&data).await?,
+            }
+        }
+    }
+    if framing == Framing::Chunked {
+        let trailers = body.trailers().await?;
+        let trailers = trailers.as_ref().map(to_headers).unwrap_or_default();
+        client.stream.write_all(&last_chunk(&trailers)).await?;
+    }
+    client.stream.flush().await?;
+    tracing::debug!("received http2 upstream response from target, sent downstream");
+    Ok(keep_alive)
+}
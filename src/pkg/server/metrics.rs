@@ -0,0 +1,461 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::pkg::{
+    spec::routes::{TargetState, UpstreamTarget},
+    telemetry::{self, RequestRecord},
+};
+
+/// Upper bounds of the request duration buckets, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    /// Counts one more for as long as the returned guard lives.
+    pub fn track(self: Arc<Self>) -> GaugeGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct GaugeGuard(Arc<Gauge>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How a metric is written out in the Prometheus text format.
+pub trait Metric: Default {
+    const KIND: &'static str;
+
+    fn render(&self, name: &str, labels: &str, out: &mut String);
+}
+
+impl Metric for Counter {
+    const KIND: &'static str = "counter";
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, self.get());
+    }
+}
+
+impl Metric for Gauge {
+    const KIND: &'static str = "gauge";
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, self.get());
+    }
+}
+
+impl Metric for Histogram {
+    const KIND: &'static str = "histogram";
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (le, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, le, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// One metric split into series by the values of its labels.
+pub struct Family<M> {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    series: Mutex<BTreeMap<Vec<String>, Arc<M>>>,
+}
+
+impl<M: Metric> Family<M> {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The series for `values`, given in the order of the family's labels.
+    pub fn with(&self, values: &[&str]) -> Arc<M> {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        Arc::clone(series.entry(key).or_default())
+    }
+
+    fn render(&self, out: &mut String) {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        if series.is_empty() {
+            return;
+        }
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, M::KIND);
+        for (values, metric) in series.iter() {
+            let labels = self
+                .labels
+                .iter()
+                .zip(values)
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            metric.render(self.name, &labels, out);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub struct Metrics {
+    pub connections_accepted: Family<Counter>,
+    pub connections_active: Family<Gauge>,
+    pub requests: Family<Counter>,
+    pub request_duration: Family<Histogram>,
+    pub upstream_connect_failures: Family<Counter>,
+    pub upstream_retries: Family<Counter>,
+    pub upstream_active: Family<Gauge>,
+    pub upstream_state: Family<Gauge>,
+    pub bytes_received: Family<Counter>,
+    pub bytes_sent: Family<Counter>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections_accepted: Family::new(
+                "liteginx_connections_accepted_total",
+                "Connections accepted by listener port.",
+                &["listener"],
+            ),
+            connections_active: Family::new(
+                "liteginx_connections_active",
+                "Connections currently open by listener port.",
+                &["listener"],
+            ),
+            requests: Family::new(
+                "liteginx_requests_total",
+                "HTTP requests answered by listener port, route and status.",
+                &["listener", "route", "status"],
+            ),
+            request_duration: Family::new(
+                "liteginx_request_duration_seconds",
+                "Time taken to answer HTTP requests by route and upstream target.",
+                &["listener", "route", "target"],
+            ),
+            upstream_connect_failures: Family::new(
+                "liteginx_upstream_connect_failures_total",
+                "Failed attempts to connect to upstream targets.",
+                &["target"],
+            ),
+            upstream_retries: Family::new(
+                "liteginx_upstream_retries_total",
+                "Connection attempts to upstream targets retried after a failure.",
+                &["target"],
+            ),
+            upstream_active: Family::new(
+                "liteginx_upstream_active",
+                "Requests and TCP sessions currently relayed to upstream targets.",
+                &["target"],
+            ),
+            upstream_state: Family::new(
+                "liteginx_upstream_state",
+                "Rotation state of upstream targets, 1 for the current one.",
+                &["target", "state"],
+            ),
+            bytes_received: Family::new(
+                "liteginx_bytes_received_total",
+                "Bytes read from clients by listener port.",
+                &["listener"],
+            ),
+            bytes_sent: Family::new(
+                "liteginx_bytes_sent_total",
+                "Bytes written to clients by listener port.",
+                &["listener"],
+            ),
+        }
+    }
+}
+
+impl Metrics {
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.connections_accepted.render(&mut out);
+        self.connections_active.render(&mut out);
+        self.requests.render(&mut out);
+        self.request_duration.render(&mut out);
+        self.upstream_connect_failures.render(&mut out);
+        self.upstream_retries.render(&mut out);
+        self.upstream_active.render(&mut out);
+        self.upstream_state.render(&mut out);
+        self.bytes_received.render(&mut out);
+        self.bytes_sent.render(&mut out);
+        out
+    }
+
+    /// Counts a request or session relayed to `target` for as long as the
+    /// returned guard lives, so draining targets show when they are done.
+    pub fn relaying(&self, target: &UpstreamTarget) -> GaugeGuard {
+        self.upstream_active
+            .with(&[&format!("{}:{}", &target.host, &target.port)])
+            .track()
+    }
+
+    /// Reports the current rotation state of `target`.
+    pub fn observe_state(&self, target: &UpstreamTarget) {
+        let name = format!("{}:{}", &target.host, &target.port);
+        let current = target.rotation.get();
+        for state in TargetState::ALL {
+            self.upstream_state
+                .with(&[&name, state.as_str()])
+                .set((state == current) as i64);
+        }
+    }
+
+    /// Counts a request answered on `listen` and how long answering took.
+    pub fn observe_request(&self, listen: u16, record: &RequestRecord, elapsed: Duration) {
+        let Some(status) = record.status else {
+            return;
+        };
+        let listener = listen.to_string();
+        let route = record.route.as_deref().unwrap_or_default();
+        self.requests
+            .with(&[&listener, route, &status.to_string()])
+            .inc();
+        // an upgraded connection would count its whole lifetime
+        if status != 101 {
+            let target = record.target.as_deref().unwrap_or_default();
+            self.request_duration
+                .with(&[&listener, route, target])
+                .observe(elapsed);
+        }
+    }
+}
+
+/// Wraps a client stream, counting the bytes read from and written to it
+/// against its listener and the request being handled.
+pub struct Counted<S> {
+    inner: S,
+    received: Arc<Counter>,
+    sent: Arc<Counter>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, listen: u16) -> Self {
+        let listener = listen.to_string();
+        Self {
+            inner,
+            received: METRICS.bytes_received.with(&[&listener]),
+            sent: METRICS.bytes_sent.with(&[&listener]),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        self.received.add(n as u64);
+        telemetry::record_received(n);
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.sent.add(n as u64);
+            telemetry::record_sent(n);
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = Metrics::default();
+        metrics.requests.with(&["8080", "/api", "200"]).add(3);
+        metrics.requests.with(&["8080", "/a\"b", "502"]).inc();
+        let duration = metrics
+            .request_duration
+            .with(&["8080", "/api", "localhost:3000"]);
+        duration.observe(Duration::from_millis(20));
+        duration.observe(Duration::from_millis(300));
+        let active = metrics.connections_active.with(&["8080"]);
+        let guard = Arc::clone(&active).track();
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE liteginx_requests_total counter\n"));
+        assert!(out.contains(
+            "liteginx_requests_total{listener=\"8080\",route=\"/api\",status=\"200\"} 3\n"
+        ));
+        assert!(out.contains("route=\"/a\\\"b\",status=\"502\"} 1\n"));
+        assert!(out.contains("liteginx_connections_active{listener=\"8080\"} 1\n"));
+        let labels = "listener=\"8080\",route=\"/api\",target=\"localhost:3000\"";
+        for (le, count) in [
+            ("0.01", 0),
+            ("0.025", 1),
+            ("0.25", 1),
+            ("0.5", 2),
+            ("+Inf", 2),
+        ] {
+            let bucket = format!(
+                "liteginx_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                labels, le, count
+            );
+            assert!(out.contains(&bucket), "missing {}", bucket);
+        }
+        assert!(out.contains(&format!(
+            "liteginx_request_duration_seconds_count{{{}}} 2\n",
+            labels
+        )));
+        // families without series are left out
+        assert!(!out.contains("liteginx_upstream_retries_total"));
+
+        drop(guard);
+        assert_eq!(active.get(), 0);
+    }
+
+    #[test]
+    fn reports_target_state() {
+        let metrics = Metrics::default();
+        let target = UpstreamTarget {
+            host: "localhost".into(),
+            port: 3000,
+            ..Default::default()
+        };
+        target.rotation.set(TargetState::Draining);
+        metrics.observe_state(&target);
+        let relaying = metrics.relaying(&target);
+
+        let out = metrics.render();
+        let series = |state, value| {
+            format!(
+                "liteginx_upstream_state{{target=\"localhost:3000\",state=\"{}\"}} {}\n",
+                state, value
+            )
+        };
+        assert!(out.contains(&series("active", 0)));
+        assert!(out.contains(&series("draining", 1)));
+        assert!(out.contains(&series("disabled", 0)));
+        assert!(out.contains("liteginx_upstream_active{target=\"localhost:3000\"} 1\n"));
+        drop(relaying);
+    }
+}
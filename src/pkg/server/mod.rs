@@ -1,3 +1,17 @@
+pub mod acme;
+pub mod admin;
+pub mod deadlines;
 pub mod downstream;
+pub mod forward;
+pub mod handoff;
+pub mod health;
 pub mod helpers;
+pub mod http;
+pub mod http2;
+pub mod metrics;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod shutdown;
+pub mod sni;
+pub mod tls;
 pub mod upstream;
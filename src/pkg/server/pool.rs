@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{timeout, Instant},
+};
+
+use crate::{
+    pkg::{
+        server::{http::HttpConn, proxy_protocol::ProxyAddrs, tls, upstream::ListenUpstream},
+        spec::routes::{PoolLimits, Timeouts, UpstreamTarget},
+    },
+    prelude::{ProxyError, Result},
+};
+
+/// An upstream HTTP/1.1 connection checked out of a `Pool`. The permit counts it
+/// against the target's `max_per_host` for as long as it is open.
+pub struct Pooled {
+    pub conn: HttpConn<TcpStream>,
+    key: String,
+    reusable: bool,
+    permit: OwnedSemaphorePermit,
+}
+
+struct Idle {
+    conn: HttpConn<TcpStream>,
+    since: Instant,
+    permit: OwnedSemaphorePermit,
+}
+
+#[derive(Default)]
+struct TargetPool {
+    idle: Vec<Idle>,
+    permits: Option<Arc<Semaphore>>,
+    h2: Option<SendRequest<Bytes>>,
+}
+
+/// Per-target keep-alive connections for an HTTP route. HTTP/2 targets share a
+/// single multiplexed connection instead.
+#[derive(Default)]
+pub struct Pool {
+    limits: PoolLimits,
+    targets: Mutex<HashMap<String, TargetPool>>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+/// Runs the HTTP/2 handshake over `stream`, leaving the connection to a task of
+/// its own.
+async fn handshake<S>(stream: S, key: String, timeouts: Timeouts) -> Result<SendRequest<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = timeout(timeouts.connect, h2::client::handshake(stream))
+        .await
+        .map_err(|_| ProxyError::UpstreamConnectTimeout)??;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("http2 connection to {} closed: {:?}", &key, e);
+        }
+    });
+    Ok(sender)
+}
+
+fn alive(conn: &HttpConn<TcpStream>) -> bool {
+    // an idle keep-alive connection has nothing to read; EOF or stray bytes mean it is done
+    matches!(conn.stream.try_read(&mut [0u8; 1]), Err(e) if e.kind() == ErrorKind::WouldBlock)
+}
+
+impl Pool {
+    pub fn new(limits: PoolLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub fn limits(&self) -> PoolLimits {
+        self.limits
+    }
+
+    fn targets(&self) -> MutexGuard<'_, HashMap<String, TargetPool>> {
+        self.targets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn take_idle(&self, key: &str) -> Option<Idle> {
+        let mut targets = self.targets();
+        let pool = targets.get_mut(key)?;
+        while let Some(idle) = pool.idle.pop() {
+            if idle.since.elapsed() < self.limits.idle_ttl && alive(&idle.conn) {
+                return Some(idle);
+            }
+        }
+        None
+    }
+
+    fn permits(&self, key: &str) -> Arc<Semaphore> {
+        let mut targets = self.targets();
+        let pool = targets.entry(key.to_string()).or_default();
+        Arc::clone(
+            pool.permits
+                .get_or_insert_with(|| Arc::new(Semaphore::new(self.limits.max_per_host))),
+        )
+    }
+
+    /// Hands out an idle connection to `target` if there is a live one, or opens a
+    /// new one once the target is under `max_per_host`. Targets that expect a PROXY
+    /// header get a fresh connection every time since the header names one client.
+    pub async fn checkout(
+        &self,
+        target: &UpstreamTarget,
+        addrs: ProxyAddrs,
+        timeouts: Timeouts,
+    ) -> Result<Pooled> {
+        let key = format!("{}:{}", &target.host, &target.port);
+        let reusable = target.proxy_protocol.is_none();
+        if reusable {
+            if let Some(idle) = self.take_idle(&key) {
+                tracing::debug!("reusing pooled connection to {}", &key);
+                return Ok(Pooled {
+                    conn: idle.conn,
+                    key,
+                    reusable,
+                    permit: idle.permit,
+                });
+            }
+        }
+        let permit = timeout(timeouts.connect, self.permits(&key).acquire_owned())
+            .await
+            .map_err(|_| ProxyError::UpstreamConnectTimeout)?
+            .map_err(|_| ProxyError::UpstreamConnectionClosed)?;
+        let stream = target.connect(addrs, timeouts).await?;
+        Ok(Pooled {
+            conn: HttpConn::new(stream, timeouts.idle),
+            key,
+            reusable,
+            permit,
+        })
+    }
+
+    /// Hands out a handle for sending requests over the shared HTTP/2 connection to
+    /// `target`, connecting again if there is none or it went away. As with
+    /// `checkout`, targets expecting a PROXY header get a connection of their own.
+    pub async fn h2_sender(
+        &self,
+        target: &UpstreamTarget,
+        addrs: ProxyAddrs,
+        timeouts: Timeouts,
+    ) -> Result<SendRequest<Bytes>> {
+        let key = format!("{}:{}", &target.host, &target.port);
+        let shared = target.proxy_protocol.is_none();
+        let cached = match shared {
+            true => self.targets().get(&key).and_then(|pool| pool.h2.clone()),
+            false => None,
+        };
+        if let Some(sender) = cached {
+            match timeout(timeouts.connect, sender.ready()).await {
+                Ok(Ok(sender)) => return Ok(sender),
+                Ok(Err(e)) => tracing::debug!("http2 connection to {} unusable: {:?}", &key, e),
+                Err(_) => tracing::debug!("http2 connection to {} saturated", &key),
+            }
+        }
+        let stream = target.connect(addrs, timeouts).await?;
+        let sender = match target.tls {
+            Some(ref conf) => {
+                let stream = timeout(timeouts.connect, tls::connect(conf, &target.host, stream))
+                    .await
+                    .map_err(|_| ProxyError::UpstreamConnectTimeout)??;
+                handshake(stream, key.clone(), timeouts).await?
+            }
+            None => handshake(stream, key.clone(), timeouts).await?,
+        };
+        if shared {
+            self.targets().entry(key).or_default().h2 = Some(sender.clone());
+        }
+        Ok(sender.ready().await?)
+    }
+
+    /// Closes the idle connections to `target` and forgets its shared HTTP/2
+    /// connection. Connections checked out finish what they are doing.
+    pub fn evict(&self, target: &UpstreamTarget) {
+        let key = format!("{}:{}", &target.host, &target.port);
+        if let Some(pool) = self.targets().get_mut(&key) {
+            pool.idle.clear();
+            pool.h2 = None;
+        }
+    }
+
+    /// Returns a connection whose last exchange left it reusable, dropping it
+    /// instead if the pool for its target is full.
+    pub fn release(&self, pooled: Pooled) {
+        if !pooled.reusable || pooled.conn.has_buffered() {
+            return;
+        }
+        let mut targets = self.targets();
+        let pool = targets.entry(pooled.key).or_default();
+        pool.idle
+            .retain(|idle| idle.since.elapsed() < self.limits.idle_ttl);
+        if pool.idle.len() < self.limits.max_idle {
+            pool.idle.push(Idle {
+                conn: pooled.conn,
+                since: Instant::now(),
+                permit: pooled.permit,
+            });
+        }
+    }
+}
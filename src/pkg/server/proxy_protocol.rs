@@ -0,0 +1,231 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    pkg::spec::config::ProxyProtocol,
+    prelude::{ProxyError, Result},
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProxyAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+impl ProxyAddrs {
+    pub fn encode(&self, version: ProxyProtocol) -> Vec<u8> {
+        match version {
+            ProxyProtocol::V1 => self.encode_v1(),
+            ProxyProtocol::V2 => self.encode_v2(),
+        }
+    }
+
+    fn encode_v1(&self) -> Vec<u8> {
+        let (source, destination) = same_family(self.source, self.destination);
+        let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+        format!(
+            "PROXY {} {} {} {} {}\r\n",
+            family,
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        )
+        .into_bytes()
+    }
+
+    fn encode_v2(&self) -> Vec<u8> {
+        let (source, destination) = same_family(self.source, self.destination);
+        let mut header = V2_SIGNATURE.to_vec();
+        // version 2, PROXY command
+        header.push(0x21);
+        match (source.ip(), destination.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                header.push(0x11);
+                header.extend_from_slice(&12u16.to_be_bytes());
+                header.extend_from_slice(&src.octets());
+                header.extend_from_slice(&dst.octets());
+            }
+            (src, dst) => {
+                header.push(0x21);
+                header.extend_from_slice(&36u16.to_be_bytes());
+                header.extend_from_slice(&to_v6(src).octets());
+                header.extend_from_slice(&to_v6(dst).octets());
+            }
+        }
+        header.extend_from_slice(&source.port().to_be_bytes());
+        header.extend_from_slice(&destination.port().to_be_bytes());
+        header
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    (
+        SocketAddr::new(IpAddr::V6(to_v6(source.ip())), source.port()),
+        SocketAddr::new(IpAddr::V6(to_v6(destination.ip())), destination.port()),
+    )
+}
+
+fn invalid(reason: &str) -> ProxyError {
+    ProxyError::InvalidProxyHeader(reason.into())
+}
+
+/// Reads a PROXY protocol v1 or v2 header off the front of `stream`, consuming
+/// exactly the header bytes so the payload that follows is left untouched.
+/// Returns `None` for `UNKNOWN`/`LOCAL` headers, where the real peer should be used.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<ProxyAddrs>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 8];
+    stream.read_exact(&mut prefix).await?;
+    if prefix.starts_with(V1_PREFIX) {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if prefix == V2_SIGNATURE[..8] {
+        let mut rest = [0u8; 8];
+        stream.read_exact(&mut rest).await?;
+        if rest[..4] != V2_SIGNATURE[8..] {
+            return Err(invalid("bad v2 signature"));
+        }
+        let mut payload = vec![0u8; u16::from_be_bytes([rest[6], rest[7]]) as usize];
+        stream.read_exact(&mut payload).await?;
+        parse_v2(rest[4], rest[5], &payload)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<ProxyAddrs>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ascii"))?;
+    let parts: Vec<&str> = line.trim_end().split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => {
+            let addr = |ip: &str, port: &str| -> Result<SocketAddr> {
+                Ok(SocketAddr::new(
+                    ip.parse().map_err(|_| invalid("v1 bad address"))?,
+                    port.parse().map_err(|_| invalid("v1 bad port"))?,
+                ))
+            };
+            Ok(Some(ProxyAddrs {
+                source: addr(src, sport)?,
+                destination: addr(dst, dport)?,
+            }))
+        }
+        _ => Err(invalid("v1 malformed header")),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> Result<Option<ProxyAddrs>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("v2 unsupported version"));
+    }
+    match ver_cmd & 0x0f {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("v2 unsupported command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    match family {
+        0x11 | 0x12 if payload.len() >= 12 => {
+            let src = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let dst = Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7]);
+            Ok(Some(ProxyAddrs {
+                source: SocketAddr::new(src.into(), port(8)),
+                destination: SocketAddr::new(dst.into(), port(10)),
+            }))
+        }
+        0x21 | 0x22 if payload.len() >= 36 => {
+            let octets = |at: usize| -> [u8; 16] { payload[at..at + 16].try_into().unwrap() };
+            Ok(Some(ProxyAddrs {
+                source: SocketAddr::new(Ipv6Addr::from(octets(0)).into(), port(32)),
+                destination: SocketAddr::new(Ipv6Addr::from(octets(16)).into(), port(34)),
+            }))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable client address
+        0x00 | 0x31 | 0x32 => Ok(None),
+        _ => Err(invalid("v2 unsupported address family")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs() -> ProxyAddrs {
+        ProxyAddrs {
+            source: "192.168.0.1:56324".parse().unwrap(),
+            destination: "10.0.0.2:443".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_roundtrip() -> Result<()> {
+        let mut data = addrs().encode(ProxyProtocol::V1);
+        assert_eq!(data, b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 443\r\n");
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut reader = data.as_slice();
+        assert_eq!(read_header(&mut reader).await?, Some(addrs()));
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn v2_roundtrip() -> Result<()> {
+        let mut data = addrs().encode(ProxyProtocol::V2);
+        data.extend_from_slice(b"payload");
+        let mut reader = data.as_slice();
+        assert_eq!(read_header(&mut reader).await?, Some(addrs()));
+        assert_eq!(reader, b"payload");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn v2_mixed_families_use_ipv6() -> Result<()> {
+        let mixed = ProxyAddrs {
+            source: "192.168.0.1:56324".parse().unwrap(),
+            destination: "[::1]:443".parse().unwrap(),
+        };
+        let data = mixed.encode(ProxyProtocol::V2);
+        let parsed = read_header(&mut data.as_slice()).await?.unwrap();
+        assert_eq!(parsed.source, "[::ffff:192.168.0.1]:56324".parse().unwrap());
+        assert_eq!(parsed.destination, mixed.destination);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() -> Result<()> {
+        let mut reader: &[u8] = b"PROXY UNKNOWN\r\nrest";
+        assert_eq!(read_header(&mut reader).await?, None);
+        assert_eq!(reader, b"rest");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_header() {
+        let mut reader: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_header(&mut reader).await.is_err());
+    }
+}
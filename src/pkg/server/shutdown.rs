@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::prelude::Result;
+
+/// Shared between listeners and their connections so that a shutdown signal
+/// stops accepting and lets in-flight connections drain.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    /// Cancelled once draining times out, dropping the connections still open.
+    force: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    /// Spawns a connection task, which is dropped along with the sockets it holds
+    /// if it is still running when draining times out.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let force = self.force.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                output = task => Some(output),
+                _ = force.cancelled() => None,
+            }
+        })
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub fn active(&self) -> usize {
+        self.tracker.len()
+    }
+
+    /// Waits up to `drain_timeout` for tracked connections to finish and closes
+    /// those still open then, returning how many were drained and how many closed.
+    pub async fn drain(&self, drain_timeout: Duration) -> (usize, usize) {
+        self.trigger();
+        self.tracker.close();
+        let active = self.active();
+        tracing::info!("draining {} active connections", active);
+        let _ = timeout(drain_timeout, self.tracker.wait()).await;
+        let remaining = self.active();
+        if remaining > 0 {
+            self.force.cancel();
+            self.tracker.wait().await;
+        }
+        (active.saturating_sub(remaining), remaining)
+    }
+}
+
+pub async fn shutdown_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => tracing::info!("received SIGTERM"),
+        _ = sigint.recv() => tracing::info!("received SIGINT"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn closes_stuck_connections_after_drain_timeout() -> Result<()> {
+        let shutdown = Shutdown::default();
+        let (mut client, server) = duplex(64);
+        shutdown.spawn(async move {
+            let _server = server;
+            std::future::pending::<()>().await
+        });
+        shutdown.spawn(tokio::time::sleep(Duration::from_millis(10)));
+        assert_eq!(shutdown.drain(Duration::from_millis(100)).await, (1, 1));
+        assert_eq!(shutdown.active(), 0);
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await?, 0);
+        Ok(())
+    }
+}
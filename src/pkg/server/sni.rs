@@ -0,0 +1,204 @@
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::{timeout_at, Instant},
+};
+
+use crate::prelude::{ProxyError, Result};
+
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: u16 = 0;
+const ALPN: u16 = 16;
+/// Largest ClientHello waited for, well above what browsers send.
+const MAX_HELLO_SIZE: usize = 64 * 1024;
+
+/// What a TLS client asked for in its ClientHello.
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+}
+
+fn malformed() -> ProxyError {
+    ProxyError::MalformedTls("invalid ClientHello".into())
+}
+
+/// Reads a big-endian integer of `n` bytes off the front of `buf`.
+fn take_int(buf: &mut &[u8], n: usize) -> Result<usize> {
+    let bytes = take(buf, n)?;
+    Ok(bytes.iter().fold(0, |acc, b| acc << 8 | *b as usize))
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(malformed());
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Reads a vector prefixed by its length in `n` bytes.
+fn take_vec<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    let len = take_int(buf, n)?;
+    take(buf, len)
+}
+
+/// Joins the handshake fragments of the records at the start of `buf`, or
+/// `None` if the ClientHello is not complete yet.
+fn handshake(mut buf: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut message = Vec::new();
+    loop {
+        if buf.len() < 5 {
+            return Ok(None);
+        }
+        if buf[0] != HANDSHAKE {
+            return Err(ProxyError::MalformedTls("not a TLS handshake".into()));
+        }
+        let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let Some(fragment) = buf.get(5..5 + len) else {
+            return Ok(None);
+        };
+        message.extend_from_slice(fragment);
+        buf = &buf[5 + len..];
+        if message.len() >= 4 {
+            let len = take_int(&mut &message[1..4], 3)?;
+            if message.len() >= 4 + len {
+                message.truncate(4 + len);
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+/// Parses the ClientHello at the start of `buf`, returning `None` if more bytes
+/// are needed.
+pub fn parse(buf: &[u8]) -> Result<Option<ClientHello>> {
+    let Some(message) = handshake(buf)? else {
+        return Ok(None);
+    };
+    let mut message = message.as_slice();
+    if take_int(&mut message, 1)? != CLIENT_HELLO as usize {
+        return Err(ProxyError::MalformedTls("expected a ClientHello".into()));
+    }
+    let mut body = take_vec(&mut message, 3)?;
+    take(&mut body, 2 + 32)?; // legacy version, random
+    take_vec(&mut body, 1)?; // session id
+    take_vec(&mut body, 2)?; // cipher suites
+    take_vec(&mut body, 1)?; // compression methods
+    let mut hello = ClientHello::default();
+    if body.is_empty() {
+        return Ok(Some(hello));
+    }
+    let mut extensions = take_vec(&mut body, 2)?;
+    while !extensions.is_empty() {
+        let kind = take_int(&mut extensions, 2)? as u16;
+        let mut data = take_vec(&mut extensions, 2)?;
+        match kind {
+            SERVER_NAME => {
+                let mut names = take_vec(&mut data, 2)?;
+                while !names.is_empty() {
+                    let name_type = take_int(&mut names, 1)?;
+                    let name = take_vec(&mut names, 2)?;
+                    if name_type == 0 {
+                        let name = std::str::from_utf8(name).map_err(|_| malformed())?;
+                        hello.server_name = Some(name.to_ascii_lowercase());
+                    }
+                }
+            }
+            ALPN => {
+                let mut protocols = take_vec(&mut data, 2)?;
+                while !protocols.is_empty() {
+                    let protocol = take_vec(&mut protocols, 1)?;
+                    hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(hello))
+}
+
+/// Reads the ClientHello off `stream` without terminating TLS, returning it
+/// along with every byte read so they can be replayed to the target.
+pub async fn peek<S>(stream: &mut S, deadline: Instant) -> Result<(ClientHello, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        if let Some(hello) = parse(&buf)? {
+            return Ok((hello, buf));
+        }
+        if buf.len() > MAX_HELLO_SIZE {
+            return Err(ProxyError::MalformedTls("ClientHello too large".into()));
+        }
+        let n = timeout_at(deadline, stream.read_buf(&mut buf))
+            .await
+            .map_err(|_| ProxyError::ClientHeaderTimeout)??;
+        if n == 0 {
+            return Err(ProxyError::DownStreamEndOfBytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{crypto::ring::default_provider, ClientConfig, ClientConnection, RootCertStore};
+
+    use super::*;
+
+    fn client_hello(server_name: &str, alpn: &[&[u8]]) -> Vec<u8> {
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        let mut conn = ClientConnection::new(
+            Arc::new(config),
+            server_name.to_string().try_into().unwrap(),
+        )
+        .unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn parses_server_name_and_alpn() -> Result<()> {
+        let data = client_hello("App.Example.com", &[b"h2", b"http/1.1"]);
+        let hello = parse(&data)?.expect("incomplete ClientHello");
+        assert_eq!(hello.server_name.as_deref(), Some("app.example.com"));
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+
+        // an IP address is not sent as SNI
+        let hello = parse(&client_hello("127.0.0.1", &[]))?.expect("incomplete ClientHello");
+        assert_eq!(hello, ClientHello::default());
+        Ok(())
+    }
+
+    #[test]
+    fn waits_for_whole_hello() -> Result<()> {
+        let data = client_hello("example.com", &[]);
+        for end in [0, 3, 5, 10, data.len() - 1] {
+            assert!(parse(&data[..end])?.is_none());
+        }
+        // the same hello split over two records
+        let message = &data[5..];
+        let mut split = Vec::new();
+        for fragment in [&message[..40], &message[40..]] {
+            split.extend_from_slice(&[HANDSHAKE, 3, 1]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        let hello = parse(&split)?.expect("incomplete ClientHello");
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,718 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, ServerName},
+    server::{danger::ClientCertVerifier, ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{sleep, Instant},
+};
+use tokio_rustls::{client, server::TlsStream, TlsAcceptor, TlsConnector};
+use x509_parser::extensions::GeneralName;
+
+use crate::{
+    pkg::{
+        server::{acme, shutdown::Shutdown},
+        spec::config::{AcmeConf, ClientAuthConf, ClientAuthMode, UpstreamTlsConf},
+    },
+    prelude::{ProxyError, Result},
+};
+
+pub const DEFAULT_CLIENT_HEADER: &str = "X-Forwarded-Client-Cert";
+
+/// How often a certificate close to expiring is warned about.
+const EXPIRY_WARNING_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// ALPN protocol of ACME TLS-ALPN-01 validation handshakes.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// The certificate a listener presents, swapped in place when it is renewed.
+/// Handshakes for ACME TLS-ALPN-01 validation get the challenge certificate
+/// for their server name instead.
+#[derive(Debug, Default)]
+struct CertStore {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN))
+        {
+            return acme::challenge_cert(hello.server_name()?);
+        }
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// TLS termination for a listener, with a certificate chain and key loaded
+/// from PEM files or obtained through ACME.
+#[derive(Clone)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    pub acme: Option<Arc<AcmeConf>>,
+    /// Header verified client certificates are forwarded in, and whatever
+    /// clients sent in it stripped from.
+    pub client_header: String,
+    /// Whether clients are asked for a certificate.
+    pub verifies_clients: bool,
+    store: Arc<CertStore>,
+    acceptor: TlsAcceptor,
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tls")
+            .field("cert", &self.cert)
+            .field("acme", &self.acme.is_some())
+            .finish()
+    }
+}
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(ProxyError::InvalidTlsConf(format!(
+            "no certificates in {}",
+            path
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?.ok_or(
+        ProxyError::InvalidTlsConf(format!("no private key in {}", path)),
+    )
+}
+
+/// Pairs a certificate chain with the key it was issued for.
+pub fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: &PrivateKeyDer<'_>,
+) -> Result<CertifiedKey> {
+    let key = any_supported_type(key)?;
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match()?;
+    Ok(certified)
+}
+
+/// When the first certificate in `certs` stops being valid.
+pub fn not_after(certs: &[CertificateDer<'_>]) -> Result<SystemTime> {
+    let invalid = || ProxyError::InvalidTlsConf("unreadable certificate".into());
+    let cert = certs.first().ok_or_else(invalid)?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).map_err(|_| invalid())?;
+    let timestamp = cert.validity().not_after.timestamp();
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64))
+}
+
+fn load_crls(path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+    let crls = rustls_pemfile::crls(&mut BufReader::new(File::open(path)?))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if crls.is_empty() {
+        return Err(ProxyError::InvalidTlsConf(format!("no crls in {}", path)));
+    }
+    Ok(crls)
+}
+
+/// Verifies client certificates against the CA bundle of `conf`, checking them
+/// against its CRL if there is one. `None` if clients aren't asked for one.
+fn client_verifier(conf: &ClientAuthConf) -> Result<Option<Arc<dyn ClientCertVerifier>>> {
+    if conf.mode == ClientAuthMode::None {
+        return Ok(None);
+    }
+    let ca = conf.ca.as_ref().ok_or(ProxyError::InvalidTlsConf(
+        "client_auth needs a ca bundle".into(),
+    ))?;
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert)?;
+    }
+    let mut builder =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(default_provider()));
+    if let Some(ref crl) = conf.crl {
+        builder = builder.with_crls(load_crls(crl)?);
+    }
+    if conf.mode == ClientAuthMode::Optional {
+        builder = builder.allow_unauthenticated();
+    }
+    let verifier = builder
+        .build()
+        .map_err(|e| ProxyError::InvalidTlsConf(format!("client_auth: {}", e)))?;
+    Ok(Some(verifier))
+}
+
+/// The identity in a verified client certificate: its subject, common name and
+/// subject alternative names as `(type, value)` pairs.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCert {
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub names: Vec<(&'static str, String)>,
+}
+
+impl ClientCert {
+    /// Reads the identity out of the certificate the client presented on `stream`.
+    pub fn from_stream<S>(stream: &TlsStream<S>) -> Option<Self> {
+        let cert = stream.get_ref().1.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from);
+        let names = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(("DNS", dns.to_string())),
+                    GeneralName::RFC822Name(email) => Some(("Email", email.to_string())),
+                    GeneralName::URI(uri) => Some(("URI", uri.to_string())),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => Some((
+                            "IP",
+                            IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string(),
+                        )),
+                        16 => Some((
+                            "IP",
+                            IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string(),
+                        )),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            names,
+        })
+    }
+
+    /// Whether `name` is the certificate's subject, common name or one of its
+    /// subject alternative names.
+    pub fn matches(&self, name: &str) -> bool {
+        self.subject == name
+            || self.common_name.as_deref() == Some(name)
+            || self.names.iter().any(|(_, value)| value == name)
+    }
+
+    /// The identity in the `Subject="...";DNS=...` form of X-Forwarded-Client-Cert.
+    pub fn header_value(&self) -> String {
+        let subject = self.subject.replace('\\', "\\\\").replace('"', "\\\"");
+        std::iter::once(format!("Subject=\"{}\"", subject))
+            .chain(
+                self.names
+                    .iter()
+                    .map(|(kind, value)| format!("{}={}", kind, value)),
+            )
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+impl Tls {
+    fn new(
+        cert: &str,
+        key: &str,
+        acme: Option<&AcmeConf>,
+        client_auth: Option<&ClientAuthConf>,
+        alpn: &[&[u8]],
+    ) -> Result<Self> {
+        let store = Arc::new(CertStore::default());
+        let builder = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?;
+        let verifier = client_auth.map(client_verifier).transpose()?.flatten();
+        let client_header = client_auth
+            .and_then(|conf| conf.header.clone())
+            .unwrap_or(DEFAULT_CLIENT_HEADER.into());
+        let verifies_clients = verifier.is_some();
+        let mut config = match verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        }
+        .with_cert_resolver(store.clone());
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        Ok(Self {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            acme: acme.cloned().map(Arc::new),
+            client_header,
+            verifies_clients,
+            store,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Builds an acceptor for `cert` and `key`, offering `alpn` protocols in order
+    /// of preference.
+    pub fn load(
+        cert: &str,
+        key: &str,
+        client_auth: Option<&ClientAuthConf>,
+        alpn: &[&[u8]],
+    ) -> Result<Self> {
+        let tls = Self::new(cert, key, None, client_auth, alpn)?;
+        tls.reload()?;
+        Ok(tls)
+    }
+
+    /// Builds an acceptor presenting the certificate ACME issued into `cert` and
+    /// `key`, once there is one. TLS-ALPN-01 validation is offered along with `alpn`.
+    pub fn acme(
+        conf: &AcmeConf,
+        cert: &str,
+        key: &str,
+        client_auth: Option<&ClientAuthConf>,
+        alpn: &[&[u8]],
+    ) -> Result<Self> {
+        let alpn = [alpn, &[ACME_TLS_ALPN]].concat();
+        let tls = Self::new(cert, key, Some(conf), client_auth, &alpn)?;
+        if let Err(e) = tls.reload() {
+            tracing::info!("no usable acme certificate in {} yet: {:?}", cert, e);
+        }
+        Ok(tls)
+    }
+
+    /// Reads `cert` and `key`, checking that they belong together.
+    pub fn read(&self) -> Result<CertifiedKey> {
+        certified_key(load_certs(&self.cert)?, &load_key(&self.key)?)
+    }
+
+    fn present(&self, certified: CertifiedKey) {
+        *self
+            .store
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(certified));
+        tracing::info!("loaded tls certificate: {}", &self.cert);
+    }
+
+    /// Reads `cert` and `key` again, presenting them to new connections.
+    pub fn reload(&self) -> Result<()> {
+        self.present(self.read()?);
+        Ok(())
+    }
+
+    /// Reloads `cert` and `key` after they changed on disk. The pair presented so
+    /// far stays in place if the new one doesn't load or has already expired.
+    pub fn rotate(&self) -> Result<()> {
+        let certified = self.read()?;
+        if not_after(&certified.cert)? <= SystemTime::now() {
+            return Err(ProxyError::InvalidTlsConf(format!(
+                "{} has expired",
+                &self.cert
+            )));
+        }
+        self.present(certified);
+        Ok(())
+    }
+
+    /// When the presented certificate stops being valid, if there is one.
+    pub fn expires(&self) -> Option<SystemTime> {
+        let current = self
+            .store
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()?;
+        not_after(&current.cert).ok()
+    }
+
+    /// Modification times of `cert` and `key`, to tell when either changes.
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+
+    pub async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.acceptor.accept(stream).await?)
+    }
+}
+
+/// How a client is named in logs and errors, by its certificate if it has one.
+pub fn client_name(cert: Option<&ClientCert>) -> String {
+    match cert {
+        Some(cert) => cert.common_name.clone().unwrap_or(cert.subject.clone()),
+        None => "without certificate".into(),
+    }
+}
+
+/// Whether the client picked HTTP/2 during the handshake.
+pub fn negotiated_h2<S>(stream: &TlsStream<S>) -> bool {
+    stream.get_ref().1.alpn_protocol() == Some(b"h2")
+}
+
+/// Whether the handshake was an ACME TLS-ALPN-01 validation, which is over
+/// once the handshake is.
+pub fn negotiated_acme<S>(stream: &TlsStream<S>) -> bool {
+    stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN)
+}
+
+/// A client config trusting `ca` if set, or the bundled webpki roots.
+pub fn client_config(ca: Option<&str>, alpn: &[&[u8]]) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// Opens TLS to `host` over `stream` with `config`.
+pub async fn handshake<S>(
+    config: ClientConfig,
+    host: &str,
+    stream: S,
+) -> Result<client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|_| ProxyError::InvalidTlsConf(format!("invalid server name {}", host)))?;
+    TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+        .map_err(|e| ProxyError::UpstreamConnectionRefused(format!("{}", &e)))
+}
+
+/// Opens TLS to an upstream target over `stream`, offering only HTTP/2. The
+/// target is verified against `conf.ca` if set, or the bundled webpki roots.
+pub async fn connect<S>(
+    conf: &UpstreamTlsConf,
+    host: &str,
+    stream: S,
+) -> Result<client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = client_config(conf.ca.as_deref(), &[b"h2"])?;
+    handshake(config, conf.server_name.as_deref().unwrap_or(host), stream).await
+}
+
+/// Logs how soon the certificate in `cert` expires once it is within `warn_before`.
+fn warn_expiry(cert: &str, expires: SystemTime, warn_before: Duration) {
+    match expires.duration_since(SystemTime::now()) {
+        Err(_) => tracing::error!("tls certificate {} has expired", cert),
+        Ok(left) if left < warn_before => tracing::warn!(
+            "tls certificate {} expires in {}",
+            cert,
+            humantime::format_duration(Duration::from_secs(left.as_secs()))
+        ),
+        Ok(_) => {}
+    }
+}
+
+/// Watches the files of the certificate presented by `listeners`, rotating it
+/// when they change, and warns daily once it is within `warn_before` of expiring.
+/// The listeners all share one cert and key. Those managed by ACME are reloaded
+/// as certificates are issued, so only their expiry is watched.
+pub async fn watch(
+    listeners: Vec<Tls>,
+    interval: Duration,
+    warn_before: Duration,
+    shutdown: Shutdown,
+) {
+    let Some(tls) = listeners.first() else {
+        return;
+    };
+    let mut modified = tls.modified();
+    // files that failed to load are tried again every tick, as they may be
+    // halfway through being replaced, but only reported once
+    let mut failed = None;
+    let mut next_warning = Instant::now();
+    loop {
+        let current = tls.modified();
+        if tls.acme.is_none() && current.is_some() && current != modified {
+            if current != failed {
+                tracing::info!("tls certificate {} changed on disk", &tls.cert);
+            }
+            match listeners.iter().try_for_each(Tls::rotate) {
+                Ok(()) => {
+                    modified = current;
+                    failed = None;
+                    next_warning = Instant::now();
+                }
+                Err(e) if current != failed => {
+                    failed = current;
+                    tracing::warn!(
+                        "keeping current tls certificate, failed to load {}: {:?}",
+                        &tls.cert,
+                        e
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+        if Instant::now() >= next_warning {
+            if let Some(expires) = tls.expires() {
+                warn_expiry(&tls.cert, expires, warn_before);
+            }
+            next_warning = Instant::now() + EXPIRY_WARNING_INTERVAL;
+        }
+        tokio::select! {
+            _ = sleep(interval) => {}
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs::File, path::PathBuf};
+
+    use rcgen::{
+        date_time_ymd, BasicConstraints, CertificateParams, CertificateRevocationListParams,
+        CertifiedIssuer, IsCa, KeyIdMethod, KeyPair, KeyUsagePurpose, RevocationReason,
+        RevokedCertParams, SerialNumber,
+    };
+    use tokio::io::duplex;
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    /// A CA issuing certificates into a directory of its own.
+    pub(crate) struct Pki {
+        dir: PathBuf,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("liteginx-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            Self { dir, ca }
+        }
+
+        pub(crate) fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_string_lossy().into_owned()
+        }
+
+        /// Issues a certificate for `name` into `{file}.pem` and its key into `{file}.key`.
+        pub(crate) fn issue(&self, name: &str, file: &str) -> CertificateDer<'static> {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()])
+                .and_then(|params| params.signed_by(&key, &self.ca))
+                .unwrap();
+            fs::write(self.path(&format!("{}.pem", file)), cert.pem()).unwrap();
+            fs::write(self.path(&format!("{}.key", file)), key.serialize_pem()).unwrap();
+            cert.der().clone()
+        }
+
+        /// Writes a CRL revoking `certs` into `crl.pem`.
+        fn revoke(&self, certs: &[&CertificateDer<'_>]) {
+            let revoked_certs = certs
+                .iter()
+                .map(|cert| {
+                    let (_, cert) = x509_parser::parse_x509_certificate(cert).unwrap();
+                    RevokedCertParams {
+                        serial_number: SerialNumber::from_slice(cert.raw_serial()),
+                        revocation_time: date_time_ymd(2024, 1, 1),
+                        reason_code: Some(RevocationReason::KeyCompromise),
+                        invalidity_date: None,
+                    }
+                })
+                .collect();
+            let crl = CertificateRevocationListParams {
+                this_update: date_time_ymd(2024, 1, 1),
+                next_update: date_time_ymd(2100, 1, 1),
+                crl_number: SerialNumber::from(1u64),
+                issuing_distribution_point: None,
+                revoked_certs,
+                key_identifier_method: KeyIdMethod::Sha256,
+            }
+            .signed_by(&self.ca)
+            .unwrap();
+            fs::write(self.path("crl.pem"), crl.pem().unwrap()).unwrap();
+        }
+
+        /// A server listening with the certificate in `server.pem`, asking clients
+        /// for one issued by the CA in `mode`.
+        fn server(&self, mode: ClientAuthMode, crl: bool) -> Tls {
+            self.issue("a.test", "server");
+            let client_auth = ClientAuthConf {
+                mode,
+                ca: Some(self.path("ca.pem")),
+                crl: crl.then(|| self.path("crl.pem")),
+                header: None,
+            };
+            let (cert, key) = (self.path("server.pem"), self.path("server.key"));
+            Tls::load(&cert, &key, Some(&client_auth), &[]).unwrap()
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// The certificate `tls` presents to a client trusting `pki`.
+    async fn served(tls: &Tls, pki: &Pki) -> Result<CertificateDer<'static>> {
+        let (client, server) = duplex(64 * 1024);
+        let config = client_config(Some(&pki.path("ca.pem")), &[])?;
+        let (accepted, connected) =
+            tokio::join!(tls.accept(server), handshake(config, "a.test", client));
+        accepted?;
+        let connected = connected?;
+        Ok(connected.get_ref().1.peer_certificates().unwrap()[0].clone())
+    }
+
+    /// The identity `tls` verified a client presenting the certificate in `file`
+    /// as, if it let the client in.
+    async fn verified(tls: &Tls, pki: &Pki, file: Option<&str>) -> Result<Option<ClientCert>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(load_certs(&pki.path("ca.pem"))?.remove(0))?;
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match file {
+            Some(file) => builder.with_client_auth_cert(
+                load_certs(&pki.path(&format!("{}.pem", file)))?,
+                load_key(&pki.path(&format!("{}.key", file)))?,
+            )?,
+            None => builder.with_no_client_auth(),
+        };
+        let (client, server) = duplex(64 * 1024);
+        let (accepted, _) = tokio::join!(tls.accept(server), handshake(config, "a.test", client));
+        Ok(ClientCert::from_stream(&accepted?))
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificates() -> Result<()> {
+        let pki = Pki::new("tls-required");
+        pki.issue("client.test", "client");
+        let tls = pki.server(ClientAuthMode::Required, false);
+        assert!(tls.verifies_clients);
+        assert!(verified(&tls, &pki, None).await.is_err());
+        let cert = verified(&tls, &pki, Some("client")).await?.unwrap();
+        assert!(cert.matches("client.test"));
+        assert!(cert.header_value().ends_with(";DNS=client.test"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_optional_client_certificates() -> Result<()> {
+        let pki = Pki::new("tls-optional");
+        pki.issue("client.test", "client");
+        let tls = pki.server(ClientAuthMode::Optional, false);
+        assert!(verified(&tls, &pki, None).await?.is_none());
+        let cert = verified(&tls, &pki, Some("client")).await?.unwrap();
+        assert!(cert.matches("client.test"));
+
+        // certificates from other CAs are still refused
+        let other = Pki::new("tls-optional-other");
+        other.issue("client.test", "client");
+        fs::copy(other.path("client.pem"), pki.path("stranger.pem"))?;
+        fs::copy(other.path("client.key"), pki.path("stranger.key"))?;
+        assert!(verified(&tls, &pki, Some("stranger")).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_revoked_client_certificates() -> Result<()> {
+        let pki = Pki::new("tls-crl");
+        pki.issue("client.test", "client");
+        let revoked = pki.issue("revoked.test", "revoked");
+        pki.revoke(&[&revoked]);
+        let tls = pki.server(ClientAuthMode::Required, true);
+        assert!(verified(&tls, &pki, Some("client")).await?.is_some());
+        assert!(verified(&tls, &pki, Some("revoked")).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotates_once_rewritten_pair_loads() -> Result<()> {
+        let pki = Pki::new("tls-watch");
+        let first = pki.issue("a.test", "server");
+        let tls = Tls::load(&pki.path("server.pem"), &pki.path("server.key"), None, &[])?;
+        let shutdown = Shutdown::default();
+        tokio::spawn(watch(
+            vec![tls.clone()],
+            Duration::from_millis(10),
+            Duration::ZERO,
+            shutdown.clone(),
+        ));
+        assert_eq!(served(&tls, &pki).await?, first);
+
+        // the certificate is replaced, then the key under the same mtime. File
+        // times are coarse, so the new certificate is dated explicitly.
+        let key = fs::read(pki.path("server.key"))?;
+        let cert_mtime = fs::metadata(pki.path("server.pem"))?.modified()?;
+        let second = pki.issue("a.test", "next");
+        fs::rename(pki.path("next.pem"), pki.path("server.pem"))?;
+        File::options()
+            .write(true)
+            .open(pki.path("server.pem"))?
+            .set_modified(cert_mtime + Duration::from_secs(1))?;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(served(&tls, &pki).await?, first);
+        let mtime = fs::metadata(pki.path("server.key"))?.modified()?;
+        fs::write(pki.path("server.key"), fs::read(pki.path("next.key"))?)?;
+        File::options()
+            .write(true)
+            .open(pki.path("server.key"))?
+            .set_modified(mtime)?;
+        assert_ne!(key, fs::read(pki.path("server.key"))?);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while served(&tls, &pki).await? != second {
+            assert!(Instant::now() < deadline, "rewritten pair never rotated in");
+            sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.trigger();
+        Ok(())
+    }
+
+    #[test]
+    #[traced_test]
+    fn warns_of_expiry() {
+        let day = Duration::from_secs(24 * 3600);
+        warn_expiry("far.pem", SystemTime::now() + 30 * day, 14 * day);
+        assert!(!logs_contain("far.pem"));
+        warn_expiry("soon.pem", SystemTime::now() + 3 * day, 14 * day);
+        assert!(logs_contain(
+            "tls certificate soon.pem expires in 2days 23h"
+        ));
+        warn_expiry("past.pem", SystemTime::now() - day, 14 * day);
+        assert!(logs_contain("tls certificate past.pem has expired"));
+    }
+}
@@ -1,109 +1,103 @@
 use crate::{
     pkg::{
         conf::settings,
-        spec::routes::{ReceiverCh, SenderCh, UpstreamTarget},
+        server::{metrics::METRICS, proxy_protocol::ProxyAddrs},
+        spec::routes::{TargetState, Timeouts, UpstreamTarget},
     },
     prelude::{ProxyError, Result},
 };
 use async_trait::async_trait;
 use humantime::parse_duration;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use std::future::Future;
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
 
 #[async_trait]
 pub trait ListenUpstream {
-    async fn listen(
-        &self,
-        client_tx: SenderCh,
-        target_rx: ReceiverCh,
-        retry_attempt: u32,
-    ) -> Result<()>;
+    async fn open(&self, addrs: ProxyAddrs, timeouts: Timeouts) -> Result<TcpStream>;
+    async fn connect(&self, addrs: ProxyAddrs, timeouts: Timeouts) -> Result<TcpStream>;
     async fn retry(
         &self,
-        client_tx: SenderCh,
-        target_rx: ReceiverCh,
+        addrs: ProxyAddrs,
+        timeouts: Timeouts,
         retry_attempt: u32,
-    ) -> Result<()>;
+    ) -> Result<TcpStream>;
 }
 
 #[async_trait]
 impl ListenUpstream for UpstreamTarget {
+    async fn open(&self, addrs: ProxyAddrs, timeouts: Timeouts) -> Result<TcpStream> {
+        let connect = async {
+            match self.resolved.is_empty() {
+                true => TcpStream::connect(format!("{}:{}", &self.host, &self.port)).await,
+                false => TcpStream::connect(&self.resolved[..]).await,
+            }
+        };
+        let mut stream = timeout(timeouts.connect, connect)
+            .await
+            .map_err(|_| ProxyError::UpstreamConnectTimeout)?
+            .map_err(|e| ProxyError::UpstreamConnectionRefused(format!("{}", &e)))?;
+        tracing::info!("connected to upstream target");
+        if let Some(version) = self.proxy_protocol {
+            stream.write_all(&addrs.encode(version)).await?;
+        }
+        Ok(stream)
+    }
+
+    async fn connect(&self, addrs: ProxyAddrs, timeouts: Timeouts) -> Result<TcpStream> {
+        self.retry(addrs, timeouts, 0).await
+    }
+
     async fn retry(
         &self,
-        client_tx: SenderCh,
-        target_rx: ReceiverCh,
+        addrs: ProxyAddrs,
+        timeouts: Timeouts,
         mut retry_attempt: u32,
-    ) -> Result<()> {
-        if retry_attempt < settings.upstream_reconnect_max_retries.unwrap_or(10) {
-            tokio::time::sleep(parse_duration(
-                &settings
-                    .upstream_reconnect_heartbeat
-                    .clone()
-                    .unwrap_or("10s".into()),
-            )?)
-            .await;
-            tracing::info!("reconnecting upstream");
-            retry_attempt += 1;
-            self.listen(client_tx, target_rx, retry_attempt).await?;
+    ) -> Result<TcpStream> {
+        let result = self.open(addrs, timeouts).await;
+        if result.is_err() {
+            METRICS
+                .upstream_connect_failures
+                .with(&[&format!("{}:{}", &self.host, &self.port)])
+                .inc();
+        }
+        match result {
+            Ok(stream) => Ok(stream),
+            // a target taken out of rotation meanwhile gets no new connections
+            Err(e)
+                if retry_attempt < settings.upstream_reconnect_max_retries.unwrap_or(10)
+                    && self.rotation.get() == TargetState::Active =>
+            {
+                tracing::error!("{:?}", &e);
+                METRICS
+                    .upstream_retries
+                    .with(&[&format!("{}:{}", &self.host, &self.port)])
+                    .inc();
+                tokio::time::sleep(parse_duration(
+                    &settings
+                        .upstream_reconnect_heartbeat
+                        .clone()
+                        .unwrap_or("10s".into()),
+                )?)
+                .await;
+                tracing::info!("reconnecting upstream");
+                retry_attempt += 1;
+                self.retry(addrs, timeouts, retry_attempt).await
+            }
+            Err(e) => Err(e),
         }
-        Ok(())
     }
+}
 
-    async fn listen(
-        &self,
-        client_tx: SenderCh,
-        mut target_rx: ReceiverCh,
-        retry_attempt: u32,
-    ) -> Result<()> {
-        if let Err(e) = async {
-            match TcpStream::connect(&format!("{}:{}", &self.host, &self.port)).await {
-                Ok(mut stream) => {
-                    tracing::info!("connected to upstream target");
-                    let mut buffer = vec![0; 1024];
-                    let (mut recv, mut send) = stream.split();
-                    tokio::select! {
-                        _ = async {
-                            loop {
-                                match recv.read(&mut buffer).await {
-                                    Ok(0) => {
-                                        //break
-                                        return Err::<(), ProxyError>(ProxyError::UpstreamReaderClosed)
-                                    },
-                                    Ok(n) => {
-                                        if let Err(e) = client_tx.send(buffer[..n].to_vec()).await{
-                                            tracing::error!("error sending msg: {}", e.to_string());
-                                            //break;
-                                            return Err::<(), ProxyError>(ProxyError::UpstreamReaderClosed)
-                                        }
-                                    }
-                                    Err(_e) => {
-                                        return Err(ProxyError::DownstreamMessageError);
-                                    }
-                                }
-                            }
-                        } => {},
-                        _ = async {
-                            while let Some(msg) = target_rx.recv().await{
-                                send.write_all(&msg).await?;
-                            }
-                            Err::<(), ProxyError>(ProxyError::UpstreamConnectionClosed)
-                        } => {},
-                        _ = tokio::signal::ctrl_c() => {}
-                    }
-                }
-                Err(e) => {
-                    return Err(ProxyError::UpstreamConnectionRefused(format!("{}", &e)));
-                }
-            }
-            Ok::<(), ProxyError>(())
+/// Runs `relaying` to `target` until it is done or the target gets disabled.
+pub async fn unless_disabled<T>(
+    target: &UpstreamTarget,
+    relaying: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        r = relaying => r,
+        _ = target.rotation.disabled() => {
+            tracing::info!("cutting off disabled target {}:{}", &target.host, &target.port);
+            Err(ProxyError::TargetDisabled)
         }
-        .await
-        {
-            tracing::error!("{:?}", &e);
-            self.retry(client_tx, target_rx, retry_attempt).await?;
-        };
-        Ok(())
     }
 }
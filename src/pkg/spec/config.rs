@@ -1,32 +1,147 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use super::routes::UpstreamTarget;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub enum Kind {
     #[serde(alias = "http")]
     Http,
     #[serde(alias = "tcp")]
     Tcp,
+    #[serde(alias = "forward")]
+    Forward,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ProxyProtocol {
+    #[serde(alias = "v1")]
+    V1,
+    #[serde(alias = "v2")]
+    V2,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TimeoutsConf {
+    pub connect: Option<String>,
+    pub client_header: Option<String>,
+    pub idle: Option<String>,
+    pub upstream_response: Option<String>,
+    pub upgrade_idle: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PoolConf {
+    pub max_idle: Option<usize>,
+    pub max_per_host: Option<usize>,
+    pub idle_ttl: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Http2Conf {
+    pub max_concurrent_streams: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UpstreamTlsConf {
+    pub ca: Option<String>,
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HealthCheckConf {
+    pub interval: Option<String>,
+    pub timeout: Option<String>,
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ForwardConf {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RequestIdConf {
+    pub header: Option<String>,
+    #[serde(default)]
+    pub trust: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct IngressSpec {
     pub kind: Kind,
     pub path: Option<String>,
     pub listen: u16,
     pub rewrite: Option<String>,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    pub timeouts: Option<TimeoutsConf>,
+    pub pool: Option<PoolConf>,
+    pub http2: Option<Http2Conf>,
+    pub forward: Option<ForwardConf>,
+    pub sni: Option<Vec<String>>,
+    pub alpn: Option<Vec<String>>,
+    pub clients: Option<Vec<String>>,
+    pub request_id: Option<RequestIdConf>,
+    #[serde(default)]
     pub targets: Vec<UpstreamTarget>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(alias = "http-01")]
+    Http01,
+    #[serde(alias = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AcmeConf {
+    pub directory: String,
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub contact: Vec<String>,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    pub state_dir: Option<String>,
+    pub ca: Option<String>,
+    pub renew_before: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum ClientAuthMode {
+    #[default]
+    #[serde(alias = "none")]
+    None,
+    #[serde(alias = "optional")]
+    Optional,
+    #[serde(alias = "required")]
+    Required,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ClientAuthConf {
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    pub ca: Option<String>,
+    pub crl: Option<String>,
+    pub header: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TlsConf {
     pub enabled: bool,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub acme: Option<AcmeConf>,
+    pub client_auth: Option<ClientAuthConf>,
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct IngressConf {
     pub name: String,
     pub spec: Vec<IngressSpec>,
@@ -1,95 +1,580 @@
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use http::HeaderName;
+use humantime::parse_duration;
 use matchit::Router;
+use serde::Deserialize;
+use serde_yaml::Value;
 
 use super::{
-    config::{IngressConf, Kind},
-    routes::{Endpoint, Route, UpstreamTarget},
+    config::{
+        AcmeConf, ClientAuthConf, ClientAuthMode, ForwardConf, HealthCheckConf, Http2Conf,
+        IngressConf, IngressSpec, Kind, PoolConf, RequestIdConf, TimeoutsConf, TlsConf,
+    },
+    routes::{
+        canonical_host, Endpoint, ForwardPolicy, HealthCheck, HostRule, Http2Limits, PoolLimits,
+        RequestIds, Route, SniRoute, Timeouts, UpstreamTarget,
+    },
+};
+use crate::{
+    pkg::{
+        conf::{settings, Defaults},
+        server::{pool::Pool, tls::Tls},
+    },
+    prelude::{ProxyError, Result},
 };
-use crate::{pkg::conf::settings, prelude::Result};
 
 impl IngressConf {
+    /// Reads the confs of `LITEGINX_CONF_DIR` and `LITEGINX_CONF_FILES`.
     pub fn new() -> Result<Vec<IngressConf>> {
-        Ok(fs::read_dir(&settings.liteginx_conf_dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "yaml"))
-            .filter_map(|yaml_path| fs::read_to_string(yaml_path.path()).ok())
-            .filter_map(|yaml| serde_yaml::from_str::<IngressConf>(&yaml).ok())
-            .collect())
+        let sources = settings
+            .liteginx_conf_dir
+            .iter()
+            .chain(settings.liteginx_conf_files.iter().flatten())
+            .collect::<Vec<_>>();
+        if sources.is_empty() {
+            return Err(ProxyError::NoConfDir);
+        }
+        Self::load(&sources)
+    }
+
+    /// Reads confs from files, directories searched recursively for `.yaml` and
+    /// `.yml` files, and glob patterns, in order and sorted by path within each.
+    /// Files are read once however often they are named or included.
+    pub fn load(sources: &[impl AsRef<Path>]) -> Result<Vec<IngressConf>> {
+        let mut reader = Reader::default();
+        for source in sources {
+            reader.source(source.as_ref())?;
+        }
+        reader.finish()
+    }
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> ProxyError {
+    ProxyError::InvalidConf(format!("{}: {}", path.display(), e))
+}
+
+/// Confs read so far, along with the files they came from.
+#[derive(Default)]
+struct Reader {
+    seen: HashSet<PathBuf>,
+    confs: Vec<(PathBuf, IngressConf)>,
+}
+
+impl Reader {
+    fn source(&mut self, source: &Path) -> Result<()> {
+        let pattern = source.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) {
+            return match source.is_dir() {
+                true => self.dir(source),
+                false => self.file(source),
+            };
+        }
+        let mut paths = glob::glob(&pattern)
+            .map_err(|e| invalid(source, e))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| invalid(source, e))?;
+        if paths.is_empty() {
+            tracing::warn!("no confs match {}", &pattern);
+        }
+        paths.sort();
+        for path in paths {
+            match path.is_dir() {
+                true => self.dir(&path)?,
+                false => self.file(&path)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `path` was read already, marking it as read otherwise.
+    fn seen(&mut self, path: &Path) -> Result<bool> {
+        let path = path.canonicalize().map_err(|e| invalid(path, e))?;
+        Ok(!self.seen.insert(path))
+    }
+
+    fn dir(&mut self, dir: &Path) -> Result<()> {
+        if self.seen(dir)? {
+            return Ok(());
+        }
+        let mut paths = fs::read_dir(dir)
+            .map_err(|e| invalid(dir, e))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|e| invalid(dir, e))?;
+        paths.sort();
+        for path in paths {
+            if path.is_dir() {
+                self.dir(&path)?;
+            } else if path
+                .extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml")
+            {
+                self.file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads every document of a YAML file, each an ingress conf, a list of
+    /// files to `include` relative to it, or both.
+    fn file(&mut self, path: &Path) -> Result<()> {
+        if self.seen(path)? {
+            return Ok(());
+        }
+        let yaml = fs::read_to_string(path).map_err(|e| invalid(path, e))?;
+        let mut includes = vec![];
+        for document in serde_yaml::Deserializer::from_str(&yaml) {
+            let mut value = Value::deserialize(document).map_err(|e| invalid(path, e))?;
+            if let Some(include) = value.as_mapping_mut().and_then(|m| m.remove("include")) {
+                let patterns = match include {
+                    Value::String(pattern) => vec![pattern],
+                    include => serde_yaml::from_value::<Vec<String>>(include)
+                        .map_err(|e| invalid(path, e))?,
+                };
+                includes.extend(patterns);
+            }
+            if value.is_null() || value.as_mapping().is_some_and(|m| m.is_empty()) {
+                continue;
+            }
+            let conf = serde_yaml::from_value(value).map_err(|e| invalid(path, e))?;
+            self.confs.push((path.to_path_buf(), conf));
+        }
+        let base = path.parent().unwrap_or(Path::new(""));
+        for pattern in includes {
+            self.source(&base.join(pattern))?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<IngressConf>> {
+        let mut names: HashMap<&str, &Path> = HashMap::new();
+        for (path, conf) in &self.confs {
+            if let Some(first) = names.insert(&conf.name, path) {
+                return Err(ProxyError::InvalidConf(format!(
+                    "{} is defined in both {} and {}",
+                    &conf.name,
+                    first.display(),
+                    path.display()
+                )));
+            }
+        }
+        Ok(self.confs.into_iter().map(|(_, conf)| conf).collect())
+    }
+}
+
+impl TimeoutsConf {
+    pub fn apply(&self, timeouts: &mut Timeouts) -> Result<()> {
+        let fields = [
+            (&self.connect, &mut timeouts.connect),
+            (&self.client_header, &mut timeouts.client_header),
+            (&self.idle, &mut timeouts.idle),
+            (&self.upstream_response, &mut timeouts.upstream_response),
+            (&self.upgrade_idle, &mut timeouts.upgrade_idle),
+        ];
+        for (conf, timeout) in fields {
+            if let Some(conf) = conf {
+                *timeout = parse_duration(conf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PoolConf {
+    pub fn apply(&self, limits: &mut PoolLimits) -> Result<()> {
+        if let Some(max_idle) = self.max_idle {
+            limits.max_idle = max_idle;
+        }
+        if let Some(max_per_host) = self.max_per_host {
+            limits.max_per_host = max_per_host;
+        }
+        if let Some(ref idle_ttl) = self.idle_ttl {
+            limits.idle_ttl = parse_duration(idle_ttl)?;
+        }
+        Ok(())
+    }
+}
+
+impl Http2Conf {
+    pub fn apply(&self, limits: &mut Http2Limits) {
+        if let Some(max_concurrent_streams) = self.max_concurrent_streams {
+            limits.max_concurrent_streams = max_concurrent_streams;
+        }
+    }
+}
+
+impl RequestIdConf {
+    pub fn apply(&self, ids: &mut RequestIds) -> Result<()> {
+        if let Some(ref header) = self.header {
+            HeaderName::try_from(header.as_str()).map_err(http::Error::from)?;
+            ids.header = header.clone();
+        }
+        ids.trust = self.trust;
+        Ok(())
+    }
+}
+
+impl HealthCheckConf {
+    pub fn apply(&self, check: &mut HealthCheck) -> Result<()> {
+        if let Some(ref interval) = self.interval {
+            check.interval = parse_duration(interval)?;
+        }
+        if let Some(ref timeout) = self.timeout {
+            check.timeout = parse_duration(timeout)?;
+        }
+        if let Some(ref service) = self.service {
+            check.service = service.clone();
+        }
+        Ok(())
+    }
+}
+
+impl HostRule {
+    /// Parses `host` or `host:port`, with IPv6 hosts in brackets.
+    pub fn parse(rule: &str) -> Result<HostRule> {
+        let invalid = || ProxyError::InvalidForwardConf(format!("invalid host rule {}", rule));
+        let (host, port) = match rule.rsplit_once(':') {
+            Some((host, port)) if !port.ends_with(']') => {
+                (host, Some(port.parse().map_err(|_| invalid())?))
+            }
+            _ => (rule, None),
+        };
+        let host = match host.strip_prefix("*.") {
+            _ if host == "*" => host.to_string(),
+            Some(domain) => format!("*.{}", canonical_host(domain).ok_or_else(invalid)?),
+            None => canonical_host(host).ok_or_else(invalid)?,
+        };
+        if host.is_empty() || host == "*." {
+            return Err(invalid());
+        }
+        Ok(HostRule { host, port })
+    }
+}
+
+impl ForwardConf {
+    /// Parses the allow and deny lists, the first of which may not be empty:
+    /// `*` has to be allowed explicitly for an open proxy.
+    pub fn load(&self) -> Result<ForwardPolicy> {
+        if self.allow.is_empty() {
+            return Err(ProxyError::InvalidForwardConf(
+                "forward proxies need an allow list, `*` allowing every destination".into(),
+            ));
+        }
+        let parse = |rules: &[String]| {
+            rules
+                .iter()
+                .map(|rule| HostRule::parse(rule))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(ForwardPolicy {
+            allow: parse(&self.allow)?,
+            deny: parse(&self.deny)?,
+        })
+    }
+}
+
+impl UpstreamTarget {
+    /// Checks the target's conf and resolves its health check.
+    fn load(&self) -> Result<UpstreamTarget> {
+        if self.tls.is_some() && !self.http2 {
+            return Err(ProxyError::InvalidTlsConf(format!(
+                "tls to {}:{} needs http2",
+                &self.host, &self.port
+            )));
+        }
+        if self.health_check.is_some() && !self.http2 {
+            return Err(ProxyError::InvalidConf(format!(
+                "health_check of {}:{} needs http2, as targets are checked over gRPC",
+                &self.host, &self.port
+            )));
+        }
+        let mut target = self.clone();
+        if let Some(ref conf) = self.health_check {
+            let mut check = HealthCheck::default();
+            conf.apply(&mut check)?;
+            target.check = Some(check);
+        }
+        Ok(target)
+    }
+}
+
+impl TlsConf {
+    /// Where the certificate and key live. With ACME they default to a directory
+    /// named after the conf under the state dir.
+    fn paths(&self, name: &str) -> Result<(String, String)> {
+        match (&self.cert, &self.key, &self.acme) {
+            (Some(cert), Some(key), _) => Ok((cert.clone(), key.clone())),
+            (None, None, Some(acme)) => Ok(acme.cert_paths(name)),
+            (_, _, Some(_)) => Err(ProxyError::InvalidTlsConf(
+                "acme needs both cert and key paths, or neither".into(),
+            )),
+            _ => Err(ProxyError::InvalidTlsConf(
+                "tls enabled without cert and key".into(),
+            )),
+        }
+    }
+
+    /// What listeners of the conf named `name` terminate TLS with, if they do:
+    /// certificate and key paths, ACME and client authentication.
+    #[allow(clippy::type_complexity)]
+    fn listener(
+        &self,
+        name: &str,
+    ) -> Result<Option<(String, String, Option<&AcmeConf>, Option<&ClientAuthConf>)>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let (cert, key) = self.paths(name)?;
+        let client_auth = self
+            .client_auth
+            .as_ref()
+            .filter(|conf| conf.mode != ClientAuthMode::None);
+        Ok(Some((cert, key, self.acme.as_ref(), client_auth)))
+    }
+
+    pub fn load(&self, name: &str, alpn: &[&[u8]]) -> Result<Tls> {
+        let (cert, key) = self.paths(name)?;
+        let client_auth = self.client_auth.as_ref();
+        match self.acme {
+            Some(ref acme) => {
+                if acme.domains.is_empty() {
+                    return Err(ProxyError::InvalidTlsConf(format!(
+                        "acme for {} without domains",
+                        name
+                    )));
+                }
+                acme.renew_before()?;
+                Tls::acme(acme, &cert, &key, client_auth, alpn)
+            }
+            None => Tls::load(&cert, &key, client_auth, alpn),
+        }
+    }
+}
+
+impl Defaults {
+    /// Route settings every conf starts from.
+    pub fn load(&self) -> Result<(Timeouts, PoolLimits, Http2Limits)> {
+        let mut timeouts = Timeouts::default();
+        let mut pool = PoolLimits::default();
+        let mut http2 = Http2Limits::default();
+        if let Some(ref conf) = self.timeouts {
+            conf.apply(&mut timeouts)?;
+        }
+        if let Some(ref conf) = self.pool {
+            conf.apply(&mut pool)?;
+        }
+        if let Some(ref conf) = self.http2 {
+            conf.apply(&mut http2);
+        }
+        Ok((timeouts, pool, http2))
     }
 }
 
 impl Route {
-    pub fn new(configs: Vec<IngressConf>) -> Result<Vec<Arc<Route>>> {
-        let paths: HashMap<u16, (Option<Router<Endpoint>>, Vec<UpstreamTarget>)> = configs
+    pub fn new(configs: &[IngressConf]) -> Result<Vec<Arc<Route>>> {
+        let (timeouts, pool, http2) = match settings.defaults {
+            Some(ref defaults) => defaults.load()?,
+            None => Default::default(),
+        };
+        // a port has one listener, expecting PROXY headers and terminating tls
+        // the same way for every spec
+        let mut listeners: HashMap<u16, (&IngressConf, &IngressSpec)> = HashMap::new();
+        for conf in configs {
+            for spec in &conf.spec {
+                let (first, first_spec) = *listeners.entry(spec.listen).or_insert((conf, spec));
+                if first_spec.proxy_protocol != spec.proxy_protocol {
+                    return Err(ProxyError::InvalidConf(format!(
+                        "{} and {} listen on port {} with different proxy_protocol",
+                        &first.name, &conf.name, spec.listen
+                    )));
+                }
+                if first.name != conf.name
+                    && first.tls.listener(&first.name)? != conf.tls.listener(&conf.name)?
+                {
+                    return Err(ProxyError::InvalidTlsConf(format!(
+                        "{} and {} listen on port {} with different tls or client_auth",
+                        &first.name, &conf.name, spec.listen
+                    )));
+                }
+            }
+        }
+        let paths: HashMap<u16, Route> = configs
             .iter()
             .flat_map(|conf| {
                 tracing::debug!("loading conf: {:?}", &conf.name);
-                conf.spec.iter()
+                conf.spec.iter().map(move |spec| (conf, spec))
             })
-            .fold(HashMap::new(), |mut paths, spec| {
+            .try_fold(HashMap::new(), |mut paths, (conf, spec)| {
                 tracing::debug!("adding listener spec: {:?}", &spec);
-                let entry = paths
-                    .entry(spec.listen)
-                    .or_insert_with(|| (None, spec.targets.clone()));
+                let entry = paths.entry(spec.listen).or_insert_with(|| Route {
+                    listen: spec.listen,
+                    proxy_protocol: spec.proxy_protocol,
+                    timeouts,
+                    pool: Pool::new(pool),
+                    http2,
+                    ..Default::default()
+                });
+                if let Some(ref timeouts) = spec.timeouts {
+                    timeouts.apply(&mut entry.timeouts)?;
+                }
+                if let Some(ref pool) = spec.pool {
+                    let mut limits = entry.pool.limits();
+                    pool.apply(&mut limits)?;
+                    entry.pool = Pool::new(limits);
+                }
+                if let Some(ref http2) = spec.http2 {
+                    http2.apply(&mut entry.http2);
+                }
+                if let Some(ref request_id) = spec.request_id {
+                    request_id.apply(&mut entry.request_id)?;
+                }
+                if conf.tls.enabled && entry.tls.is_none() {
+                    let alpn: &[&[u8]] = match spec.kind {
+                        Kind::Http => &[b"h2", b"http/1.1"],
+                        Kind::Forward => &[b"http/1.1"],
+                        Kind::Tcp => &[],
+                    };
+                    entry.tls = Some(conf.tls.load(&conf.name, alpn)?);
+                }
+                if let Kind::Forward = spec.kind {
+                    if entry.forward.is_some() || entry.endpoints.is_some() {
+                        tracing::warn!(
+                            "port {} already has routes, ignoring forward spec",
+                            spec.listen
+                        );
+                        return Ok(paths);
+                    }
+                    entry.forward = Some(
+                        spec.forward
+                            .as_ref()
+                            .unwrap_or(&ForwardConf::default())
+                            .load()?,
+                    );
+                }
                 if let Kind::Http = spec.kind {
-                    let router = entry.0.get_or_insert_with(Router::new);
-                    let path = spec
-                        .path
-                        .clone()
-                        .expect("http spec missing mandatory field path".into());
+                    if entry.forward.is_some() {
+                        tracing::warn!(
+                            "port {} is a forward proxy, ignoring http spec",
+                            spec.listen
+                        );
+                        return Ok(paths);
+                    }
+                    let router = entry.endpoints.get_or_insert_with(Router::new);
+                    // checked here rather than panicked on, as reloads load
+                    // confs in the running proxy
+                    let path = spec.path.clone().ok_or_else(|| {
+                        ProxyError::InvalidConf(format!(
+                            "http spec of {} on port {} without path",
+                            conf.name, spec.listen
+                        ))
+                    })?;
                     if router.at(&path).is_ok() {
                         tracing::warn!("{} conflicts with existing endpoint", &path);
-                        return paths;
+                        return Ok(paths);
                     }
 
-                    let rewrite = spec.rewrite.clone();
-                    if let Err(err) = router.insert(path.clone(), Endpoint { path, rewrite }) {
+                    let clients = spec.clients.clone().unwrap_or_default();
+                    let verifies = entry.tls.as_ref().is_some_and(|tls| tls.verifies_clients);
+                    if !clients.is_empty() && !verifies {
+                        return Err(ProxyError::InvalidTlsConf(format!(
+                            "clients on {} need tls.client_auth",
+                            &path
+                        )));
+                    }
+                    let endpoint = Endpoint {
+                        path: path.clone(),
+                        rewrite: spec.rewrite.clone(),
+                        clients,
+                    };
+                    if let Err(err) = router.insert(path, endpoint.clone()) {
                         tracing::error!("Failed to insert: {}", err);
-                        return paths;
+                        return Ok(paths);
                     }
+                    entry.paths.push(endpoint);
                 }
-                spec.targets.iter().for_each(|target| {
-                    if !entry.1.contains(target) {
-                        entry.1.push(target.clone());
+                if let Some(ref names) = spec.sni {
+                    let Kind::Tcp = spec.kind else {
+                        return Err(ProxyError::InvalidTlsConf(format!(
+                            "sni routing on port {} needs a tcp spec",
+                            spec.listen
+                        )));
+                    };
+                    entry.sni.push(SniRoute {
+                        names: names
+                            .iter()
+                            .map(|name| {
+                                Ok(HostRule {
+                                    host: canonical_host(name).ok_or_else(|| {
+                                        ProxyError::InvalidTlsConf(format!("invalid sni {}", name))
+                                    })?,
+                                    port: None,
+                                })
+                            })
+                            .collect::<Result<_>>()?,
+                        alpn: spec.alpn.clone().unwrap_or_default(),
+                        targets: spec
+                            .targets
+                            .iter()
+                            .map(UpstreamTarget::load)
+                            .collect::<Result<_>>()?,
+                    });
+                    return Ok(paths);
+                }
+                for target in &spec.targets {
+                    if !entry.targets.contains(target) {
+                        entry.targets.push(target.load()?);
                     }
-                });
-                paths
-            });
-        let routes = paths
-            .into_iter()
-            .map(|(listen, (endpoints, targets))| Route {
-                listen,
-                endpoints,
-                targets,
-                ..Default::default()
-            })
-            .map(Arc::new)
-            .collect();
+                }
+                Ok::<_, ProxyError>(paths)
+            })?;
+        if let Some(route) = paths
+            .values()
+            .find(|route| route.tls.is_some() && !route.sni.is_empty())
+        {
+            return Err(ProxyError::InvalidTlsConf(format!(
+                "port {} passes tls through by sni, it can't terminate tls too",
+                route.listen
+            )));
+        }
+        let routes = paths.into_values().map(Arc::new).collect();
         Ok(routes)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use tracing_test::traced_test;
 
     use super::*;
+    use crate::pkg::{
+        server::{helpers::match_prefix, sni::ClientHello, tls::tests::Pki},
+        spec::config::AcmeChallenge,
+    };
 
     #[test]
     #[traced_test]
     fn test_load_http_test() -> Result<()> {
-        let configs = IngressConf::new()?;
-        let routes = Route::new(configs)?;
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
 
         let route = routes
             .iter()
-            .find(|r| r.listen == 80 && r.endpoints.iter().any(|e| e.path == "/one"))
+            .find(|r| r.listen == 5000)
             .expect("Missing one-ingress route");
 
-        assert_eq!(route.listen, 80);
-        assert_eq!(route.endpoints.len(), 2);
-        let ep = &route.endpoints[0];
+        let router = route
+            .endpoints
+            .as_ref()
+            .expect("http route without endpoints");
+        let ep = router.at("/one").expect("missing /one endpoint").value;
         assert_eq!(ep.path, "/one");
         assert!(ep.rewrite.is_none());
 
@@ -98,23 +583,38 @@ mod tests {
         assert_eq!(target.host, "localhost");
         assert_eq!(target.port, 3000);
 
+        assert_eq!(route.pool.limits().max_idle, 4);
+        assert_eq!(route.pool.limits().idle_ttl, Duration::from_secs(30));
+        assert_eq!(
+            route.pool.limits().max_per_host,
+            PoolLimits::default().max_per_host
+        );
+
+        assert_eq!(route.request_id.header, "X-Correlation-Id");
+        assert_eq!(route.request_id.resolve(Some("abc-123")), "abc-123");
+        let generated = route.request_id.resolve(Some("not printable"));
+        assert_eq!(generated.len(), 32);
+        assert_ne!(generated, route.request_id.resolve(None));
+
         Ok(())
     }
 
     #[test]
     #[traced_test]
     fn load_http_with_rewrite_test() -> Result<()> {
-        let configs = IngressConf::new()?;
-        let routes = Route::new(configs)?;
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
 
         let route = routes
             .iter()
-            .find(|r| r.listen == 80 && r.endpoints.iter().any(|e| e.path == "/two"))
+            .find(|r| r.listen == 5000)
             .expect("Missing two-ingress route");
 
-        assert_eq!(route.listen, 80);
-        assert_eq!(route.endpoints.len(), 2);
-        let ep = &route.endpoints[1];
+        let router = route
+            .endpoints
+            .as_ref()
+            .expect("http route without endpoints");
+        let ep = router.at("/two").expect("missing /two endpoint").value;
         assert_eq!(ep.path, "/two");
         assert_eq!(ep.rewrite.as_deref(), Some("/"));
 
@@ -122,6 +622,254 @@ mod tests {
         let target = &route.targets[0];
         assert_eq!(target.host, "localhost");
         assert_eq!(target.port, 3000);
+        assert!(!target.http2);
+        assert_eq!(route.http2.max_concurrent_streams, 50);
+        assert!(route.tls.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    #[traced_test]
+    fn load_grpc_target() -> Result<()> {
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
+
+        let route = routes
+            .iter()
+            .find(|r| r.listen == 5002)
+            .expect("Missing grpc-ingress route");
+
+        let router = route
+            .endpoints
+            .as_ref()
+            .expect("http route without endpoints");
+        let ep = match_prefix(router, "/helloworld.Greeter/SayHello")
+            .expect("missing /helloworld.Greeter endpoint");
+        assert_eq!(ep.path, "/helloworld.Greeter");
+
+        assert_eq!(route.targets.len(), 1);
+        let target = &route.targets[0];
+        assert_eq!(target.port, 50051);
+        assert!(target.http2);
+        assert!(target.health.is_healthy());
+        let check = target.check.as_ref().expect("missing health check");
+        assert_eq!(check.interval, Duration::from_secs(5));
+        assert_eq!(check.timeout, HealthCheck::default().timeout);
+        assert!(check.service.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[traced_test]
+    fn load_forward() -> Result<()> {
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
+
+        let route = routes
+            .iter()
+            .find(|r| r.listen == 5003)
+            .expect("Missing forward-ingress route");
+        assert!(route.endpoints.is_none());
+        assert!(route.targets.is_empty());
+
+        let policy = route.forward.as_ref().expect("missing forward policy");
+        assert!(policy.permits("api.example.com", 443));
+        assert!(policy.permits("a.b.Example.com", 443));
+        assert!(!policy.permits("api.example.com", 80));
+        assert!(!policy.permits("example.com", 443));
+        assert!(!policy.permits("admin.example.com", 443));
+        assert!(policy.permits("localhost", 8080));
+        assert!(!policy.permits("other.org", 443));
+
+        Ok(())
+    }
+
+    #[test]
+    #[traced_test]
+    fn load_sni_passthrough() -> Result<()> {
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
+
+        let route = routes
+            .iter()
+            .find(|r| r.listen == 5004)
+            .expect("Missing sni-ingress route");
+        assert!(route.endpoints.is_none());
+        assert_eq!(route.sni.len(), 2);
+        assert_eq!(route.targets.len(), 1);
+        assert_eq!(route.targets[0].port, 4445);
+
+        let hello = |name: &str, alpn: &[&str]| ClientHello {
+            server_name: Some(name.into()),
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+        };
+        let port = |hello: &ClientHello| {
+            route
+                .sni
+                .iter()
+                .find(|r| r.matches(hello))
+                .map(|r| r.targets[0].port)
+        };
+        assert_eq!(port(&hello("app.example.com", &[])), Some(4443));
+        assert_eq!(port(&hello("eu.app.example.com", &["h2"])), Some(4443));
+        assert_eq!(
+            port(&hello("api.example.com", &["h2", "http/1.1"])),
+            Some(4444)
+        );
+        assert_eq!(port(&hello("api.example.com", &["http/1.1"])), None);
+        assert_eq!(port(&ClientHello::default()), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_host_rules() -> Result<()> {
+        let rule = HostRule::parse("Example.com:8443")?;
+        assert_eq!(rule.host, "example.com");
+        assert_eq!(rule.port, Some(8443));
+        assert_eq!(HostRule::parse("*")?.port, None);
+        let rule = HostRule::parse("[::1]:443")?;
+        assert_eq!(rule.host, "::1");
+        assert_eq!(rule.to_string(), "[::1]:443");
+        assert!(rule.matches("[::1]", 443));
+        assert_eq!(HostRule::parse("[::1]")?.port, None);
+        assert_eq!(HostRule::parse("*.Example.com.")?.host, "*.example.com");
+        assert!(HostRule::parse("example.com:https").is_err());
+        assert!(HostRule::parse(":443").is_err());
+        assert!(HostRule::parse("127.1").is_err());
+        assert!(!ForwardPolicy::default().permits("anything", 1));
+        assert!(ForwardConf::default().load().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forward_deny_rules_match_any_spelling() -> Result<()> {
+        let policy = ForwardConf {
+            allow: vec!["*".into()],
+            deny: vec!["evil.com".into(), "[::1]".into(), "10.0.0.1".into()],
+        }
+        .load()?;
+        assert!(policy.permits("good.com", 443));
+        for host in [
+            "evil.com.",
+            "EVIL.com",
+            "::1",
+            "[0:0:0:0:0:0:0:1]",
+            "[::ffff:10.0.0.1]",
+            "127.1",
+            "2130706433",
+            "0x7f.0.0.1",
+            "[fe80::1%eth0]",
+        ] {
+            assert!(!policy.permits(host, 443), "{} permitted", host);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_differing_tls_on_shared_port() -> Result<()> {
+        let conf = |name: &str, extra: &str, tls: &str| -> IngressConf {
+            serde_yaml::from_str(&format!(
+                "name: {}
+spec:
+- kind: http
+  listen: 6200
+  path: /{}
+{}  targets: [{{host: localhost, port: 3000}}]
+tls: {}
+",
+                name, name, extra, tls
+            ))
+            .unwrap()
+        };
+        let paths = "enabled: true, cert: a.pem, key: a.key";
+        let required = format!("{{{}, client_auth: {{mode: required, ca: ca.pem}}}}", paths);
+        let loaded = |configs: &[IngressConf]| match Route::new(configs) {
+            Err(ProxyError::InvalidTlsConf(e)) => e,
+            r => panic!("loaded {:?}", r.map(|_| ())),
+        };
+        assert_eq!(
+            loaded(&[conf("a", "", &required), conf("b", "", "{enabled: false}")]),
+            "a and b listen on port 6200 with different tls or client_auth"
+        );
+        let proxied = conf("b", "  proxy_protocol: true\n", "{enabled: false}");
+        assert!(matches!(
+            Route::new(&[conf("a", "", "{enabled: false}"), proxied]),
+            Err(ProxyError::InvalidConf(e))
+                if e == "a and b listen on port 6200 with different proxy_protocol"
+        ));
+        let optional = required.replace("required", "optional");
+        assert!(
+            loaded(&[conf("a", "", &required), conf("b", "", &optional)])
+                .contains("different tls or client_auth")
+        );
+
+        // clients are checked against the listener, not the conf they are in
+        let pki = Pki::new("loader-clients");
+        pki.issue("a.test", "a");
+        let tls = |client_auth: &str| {
+            format!(
+                "{{enabled: true, cert: {}, key: {}{}}}",
+                pki.path("a.pem"),
+                pki.path("a.key"),
+                client_auth
+            )
+        };
+        let clients = "  clients: [client.test]\n";
+        let required = tls(&format!(
+            ", client_auth: {{mode: required, ca: {}}}",
+            pki.path("ca.pem")
+        ));
+        let routes = Route::new(&[conf("a", "", &required), conf("b", clients, &required)])?;
+        assert!(routes[0]
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.verifies_clients));
+        assert_eq!(
+            loaded(&[
+                conf("a", "", &tls("")),
+                conf("b", clients, &tls(", client_auth: {mode: none}")),
+            ]),
+            "clients on /b need tls.client_auth"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn target_tls_and_health_checks_need_http2() {
+        let target = |options: &str| -> UpstreamTarget {
+            serde_yaml::from_str(&format!("{{host: localhost, port: 50051, {}}}", options)).unwrap()
+        };
+        assert!(target("http2: true, health_check: {interval: 1s}")
+            .load()
+            .is_ok());
+        assert!(matches!(
+            target("health_check: {interval: 1s}").load(),
+            Err(ProxyError::InvalidConf(e)) if e.contains("needs http2")
+        ));
+        assert!(matches!(
+            target("tls: {}").load(),
+            Err(ProxyError::InvalidTlsConf(e)) if e.contains("needs http2")
+        ));
+    }
+
+    #[test]
+    fn load_acme() -> Result<()> {
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
+
+        let route = routes
+            .iter()
+            .find(|r| r.listen == 5005)
+            .expect("Missing acme-ingress route");
+        let tls = route.tls.as_ref().expect("Missing tls");
+        let acme = tls.acme.as_ref().expect("Missing acme conf");
+        assert_eq!(acme.challenge, AcmeChallenge::TlsAlpn01);
+        assert_eq!(acme.renew_before()?, Duration::from_secs(20 * 24 * 3600));
+        assert_eq!(tls.cert, "target/acme-fixture/acme-ingress/cert.pem");
+        assert_eq!(tls.key, "target/acme-fixture/acme-ingress/key.pem");
 
         Ok(())
     }
@@ -129,22 +877,128 @@ mod tests {
     #[test]
     #[traced_test]
     fn load_tcp() -> Result<()> {
-        let configs = IngressConf::new()?;
-        let routes = Route::new(configs)?;
+        let configs = IngressConf::load(&["fixtures"])?;
+        let routes = Route::new(&configs)?;
 
         tracing::debug!("routes: {:?}", &routes);
         let route = routes
             .iter()
-            .find(|r| r.listen == 4000)
+            .find(|r| r.listen == 4001)
             .expect("Missing tcptest-ingress route");
 
-        assert!(route.endpoints.is_empty()); // No path or rewrite for TCP
+        assert!(route.endpoints.is_none()); // No path or rewrite for TCP
+        assert!(!route.proxy_protocol);
+        assert_eq!(route.timeouts.idle, Duration::from_secs(30));
+        assert_eq!(route.timeouts.connect, Timeouts::default().connect);
+        assert_eq!(route.request_id.header, "X-Request-Id");
+        assert!(!route.request_id.trust);
 
         assert_eq!(route.targets.len(), 1);
         let target = &route.targets[0];
         assert_eq!(target.host, "localhost");
-        assert_eq!(target.port, 4001);
+        assert_eq!(target.port, 4000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_defaults() -> Result<()> {
+        let defaults = Defaults {
+            timeouts: Some(TimeoutsConf {
+                connect: Some("3s".into()),
+                ..Default::default()
+            }),
+            pool: Some(PoolConf {
+                max_idle: Some(4),
+                ..Default::default()
+            }),
+            http2: None,
+        };
+        let (timeouts, pool, http2) = defaults.load()?;
+        assert_eq!(timeouts.connect, Duration::from_secs(3));
+        assert_eq!(timeouts.idle, Timeouts::default().idle);
+        assert_eq!(pool.max_idle, 4);
+        assert_eq!(pool.max_per_host, PoolLimits::default().max_per_host);
+        assert_eq!(
+            http2.max_concurrent_streams,
+            Http2Limits::default().max_concurrent_streams
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn loads_nested_and_multi_document() -> Result<()> {
+        let configs = IngressConf::load(&["fixtures"])?;
+        let names = configs.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+        // sorted by path, with nested/four.yml read in place of its directory
+        assert_eq!(
+            names,
+            [
+                "acme-ingress",
+                "forward-ingress",
+                "grpc-ingress",
+                "four-ingress",
+                "five-ingress",
+                "one-ingress",
+                "sni-ingress",
+                "tcptest-ingress",
+                "two-ingress",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn loads_includes_and_rejects_duplicates() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("liteginx-confs-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d"))?;
+        let conf = |name: &str, port: u16| {
+            format!(
+                "name: {}\nspec:\n- kind: tcp\n  listen: {}\n  targets: [{{host: localhost, port: 4000}}]\ntls: {{enabled: false}}\n",
+                name, port
+            )
+        };
+        fs::write(
+            dir.join("main.yaml"),
+            "include: [conf.d/*.yaml, main.yaml]\n",
+        )?;
+        fs::write(dir.join("conf.d/b.yaml"), conf("b", 6002))?;
+        fs::write(dir.join("conf.d/a.yaml"), conf("a", 6001))?;
+
+        let configs = IngressConf::load(&[dir.join("main.yaml")])?;
+        let names = configs.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+
+        fs::write(dir.join("conf.d/c.yaml"), conf("a", 6003))?;
+        let loaded = IngressConf::load(&[dir.join("main.yaml")]);
+        fs::remove_dir_all(&dir)?;
+        assert!(
+            matches!(loaded, Err(ProxyError::InvalidConf(e)) if e.contains("a is defined in both"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reloads_reject_http_specs_without_path() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("liteginx-pathless-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let conf = |path: &str| {
+            format!(
+                "name: a\nspec:\n- kind: http\n  listen: 6004\n{}  targets: [{{host: localhost, port: 3000}}]\ntls: {{enabled: false}}\n",
+                path
+            )
+        };
+        fs::write(dir.join("a.yaml"), conf("  path: /a\n"))?;
+        assert_eq!(Route::new(&IngressConf::load(&[&dir])?)?.len(), 1);
 
+        // what a reload reads once the path is edited out
+        fs::write(dir.join("a.yaml"), conf(""))?;
+        let reloaded = IngressConf::load(&[&dir]).and_then(|confs| Route::new(&confs).map(|_| ()));
+        fs::remove_dir_all(&dir)?;
+        assert!(matches!(
+            reloaded,
+            Err(ProxyError::InvalidConf(e)) if e == "http spec of a on port 6004 without path"
+        ));
         Ok(())
     }
 }
@@ -1,18 +1,144 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use http::{HeaderMap, HeaderName};
 use matchit::Router;
-use serde::Deserialize;
-use tokio::sync::mpsc::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::config::{HealthCheckConf, ProxyProtocol, UpstreamTlsConf};
+use crate::pkg::server::{
+    http::{RequestHead, ResponseHead},
+    pool::Pool,
+    sni::ClientHello,
+    tls::{ClientCert, Tls, DEFAULT_CLIENT_HEADER},
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Endpoint {
     pub path: String,
     pub rewrite: Option<String>,
+    #[serde(default)]
+    pub clients: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Default, Clone)]
+impl Endpoint {
+    /// Whether a client presenting `cert` may use the endpoint. Endpoints without
+    /// `clients` are open to any client.
+    pub fn admits(&self, cert: Option<&ClientCert>) -> bool {
+        self.clients.is_empty()
+            || cert.is_some_and(|cert| self.clients.iter().any(|name| cert.matches(name)))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct UpstreamTarget {
     pub host: String,
     pub port: u16,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    #[serde(default)]
+    pub http2: bool,
+    pub tls: Option<UpstreamTlsConf>,
+    pub health_check: Option<HealthCheckConf>,
+    #[serde(skip)]
+    pub check: Option<HealthCheck>,
+    #[serde(skip)]
+    pub health: TargetHealth,
+    #[serde(skip)]
+    pub rotation: Rotation,
+    /// Addresses connected to instead of resolving `host` again, once they were
+    /// checked against a forward proxy's deny rules.
+    #[serde(skip)]
+    pub resolved: Vec<SocketAddr>,
+}
+
+impl UpstreamTarget {
+    /// Whether new requests and connections may go to the target.
+    pub fn in_rotation(&self) -> bool {
+        self.health.is_healthy() && self.rotation.get() == TargetState::Active
+    }
+}
+
+/// Outcome of the last health check of a target, shared by every clone of it.
+/// Targets without health checks stay healthy.
+#[derive(Debug, Clone)]
+pub struct TargetHealth(Arc<AtomicBool>);
+
+impl Default for TargetHealth {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl TargetHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Records a check result, returning whether it changed the target's health.
+    pub fn set(&self, healthy: bool) -> bool {
+        self.0.swap(healthy, Ordering::Relaxed) != healthy
+    }
+}
+
+/// Whether a target was taken out of rotation through the admin API. Draining
+/// targets get no new requests or connections but finish the ones they have,
+/// disabled ones are cut off from those too and are no longer health checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    Active,
+    Draining,
+    Disabled,
+}
+
+impl TargetState {
+    pub const ALL: [TargetState; 3] = [Self::Active, Self::Draining, Self::Disabled];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Draining => "draining",
+            Self::Disabled => "disabled",
+        }
+    }
+}
+
+/// The `TargetState` of a target, shared by every clone of it.
+#[derive(Debug, Clone)]
+pub struct Rotation(Arc<watch::Sender<TargetState>>);
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(TargetState::Active)))
+    }
+}
+
+impl Rotation {
+    pub fn get(&self) -> TargetState {
+        *self.0.borrow()
+    }
+
+    /// Moves the target to `state`, returning whether that changed anything.
+    pub fn set(&self, state: TargetState) -> bool {
+        self.0
+            .send_if_modified(|current| std::mem::replace(current, state) != state)
+    }
+
+    /// Resolves once the target is disabled.
+    pub async fn disabled(&self) {
+        let mut state = self.0.subscribe();
+        // the sender lives as long as `self`, so waiting cannot fail
+        let _ = state
+            .wait_for(|state| *state == TargetState::Disabled)
+            .await;
+    }
 }
 
 impl PartialEq for UpstreamTarget {
@@ -21,12 +147,316 @@ impl PartialEq for UpstreamTarget {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub client_header: Duration,
+    pub idle: Duration,
+    pub upstream_response: Duration,
+    pub upgrade_idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            client_header: Duration::from_secs(60),
+            idle: Duration::from_secs(600),
+            upstream_response: Duration::from_secs(60),
+            upgrade_idle: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub service: String,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            service: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolLimits {
+    pub max_idle: usize,
+    pub max_per_host: usize,
+    pub idle_ttl: Duration,
+}
+
+impl Default for PoolLimits {
+    fn default() -> Self {
+        Self {
+            max_idle: 16,
+            max_per_host: 1024,
+            idle_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Http2Limits {
+    pub max_concurrent_streams: u32,
+}
+
+impl Default for Http2Limits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: 100,
+        }
+    }
+}
+
+/// The form of `host` rules are matched against: lowercase, without a trailing
+/// dot and, for IP addresses, without brackets and in canonical form. `None` for
+/// hosts resolvers may take as an address in another notation, like `127.1`.
+pub fn canonical_host(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let bare = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(&host);
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Some(ip.to_canonical().to_string());
+    }
+    let last = host.rsplit('.').next().unwrap_or_default();
+    let numeric = last.bytes().all(|b| b.is_ascii_digit()) || last.starts_with("0x");
+    match numeric || host.contains(':') || host.contains('%') {
+        true => None,
+        false => Some(host),
+    }
+}
+
+/// A destination on a forward proxy allow or deny list. Hosts starting with `*.`
+/// match any subdomain and `*` matches every host; no port matches any port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostRule {
+    /// In the form of `canonical_host`.
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl HostRule {
+    pub fn matches_host(&self, host: &str) -> bool {
+        let Some(host) = canonical_host(host) else {
+            return false;
+        };
+        match self.host.strip_prefix("*.") {
+            _ if self.host == "*" => true,
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.')),
+            None => self.host == host,
+        }
+    }
+
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.matches_host(host) && self.port.is_none_or(|p| p == port)
+    }
+}
+
+impl std::fmt::Display for HostRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match self.port {
+            Some(port) => write!(f, "{}:{}", host, port),
+            None => write!(f, "{}", host),
+        }
+    }
+}
+
+/// Which destinations a forward proxy route may reach: those allowed and not
+/// denied. Nothing is allowed by an empty allow list.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardPolicy {
+    pub allow: Vec<HostRule>,
+    pub deny: Vec<HostRule>,
+}
+
+impl ForwardPolicy {
+    pub fn permits(&self, host: &str, port: u16) -> bool {
+        canonical_host(host).is_some()
+            && !self.denies(host, port)
+            && self.allow.iter().any(|rule| rule.matches(host, port))
+    }
+
+    pub fn denies(&self, host: &str, port: u16) -> bool {
+        self.deny.iter().any(|rule| rule.matches(host, port))
+    }
+}
+
+/// Targets for TLS connections passed through untouched, picked by the server
+/// name in the ClientHello and, if `alpn` is set, one of the protocols offered.
+#[derive(Debug, Default)]
+pub struct SniRoute {
+    pub names: Vec<HostRule>,
+    pub alpn: Vec<String>,
+    pub targets: Vec<UpstreamTarget>,
+}
+
+impl SniRoute {
+    pub fn matches(&self, hello: &ClientHello) -> bool {
+        hello
+            .server_name
+            .as_ref()
+            .is_some_and(|name| self.names.iter().any(|rule| rule.matches_host(name)))
+            && (self.alpn.is_empty() || hello.alpn.iter().any(|p| self.alpn.contains(p)))
+    }
+}
+
+/// How requests are given ids: clients' own ids in `header` are kept only if
+/// `trust`ed, a random one is generated otherwise.
+#[derive(Debug, Clone)]
+pub struct RequestIds {
+    pub header: String,
+    pub trust: bool,
+}
+
+impl Default for RequestIds {
+    fn default() -> Self {
+        Self {
+            header: "X-Request-Id".into(),
+            trust: false,
+        }
+    }
+}
+
+impl RequestIds {
+    /// The id of a request that came with `incoming` in its header.
+    pub fn resolve(&self, incoming: Option<&str>) -> String {
+        match incoming {
+            Some(id) if self.trust && is_valid_id(id) => id.to_string(),
+            _ => format!("{:032x}", rand::random::<u128>()),
+        }
+    }
+
+    /// Echoes the id `request` was sent upstream with on its `response`.
+    pub fn echo(&self, request: &RequestHead, response: &mut ResponseHead) {
+        if let Some(id) = request.header(&self.header) {
+            response.set_header(&self.header, id);
+        }
+    }
+
+    /// The id header of an HTTP/2 request, to add to its response.
+    pub fn echoed(&self, request: &HeaderMap) -> HeaderMap {
+        let mut echoed = HeaderMap::new();
+        if let (Ok(name), Some(id)) = (
+            HeaderName::try_from(self.header.as_str()),
+            request.get(self.header.as_str()),
+        ) {
+            echoed.insert(name, id.clone());
+        }
+        echoed
+    }
+}
+
+/// Ids taken from clients end up in logs and upstream requests, so only short
+/// printable ones are kept.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 200 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
 #[derive(Debug, Default)]
 pub struct Route {
     pub listen: u16,
     pub endpoints: Option<Router<Endpoint>>,
+    /// The endpoints of `endpoints` in the order they were added, as the router
+    /// can't list them.
+    pub paths: Vec<Endpoint>,
     pub targets: Vec<UpstreamTarget>,
+    pub proxy_protocol: bool,
+    pub timeouts: Timeouts,
+    pub pool: Pool,
+    pub http2: Http2Limits,
+    pub request_id: RequestIds,
+    pub tls: Option<Tls>,
+    pub forward: Option<ForwardPolicy>,
+    pub sni: Vec<SniRoute>,
 }
 
-pub type SenderCh = Sender<Vec<u8>>;
-pub type ReceiverCh = Receiver<Vec<u8>>;
+impl Route {
+    /// The header client certificates are forwarded to targets in, which is
+    /// stripped from requests on every route so clients can't fill it in.
+    pub fn client_header(&self) -> &str {
+        self.tls
+            .as_ref()
+            .map_or(DEFAULT_CLIENT_HEADER, |tls| tls.client_header.as_str())
+    }
+
+    /// The route's own targets followed by those of its `sni` routes.
+    pub fn all_targets(&self) -> impl Iterator<Item = &UpstreamTarget> {
+        self.targets
+            .iter()
+            .chain(self.sni.iter().flat_map(|sni| &sni.targets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::server::http::Header;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn keeps_only_trusted_ids() {
+        let ids = RequestIds::default();
+        let generated = ids.resolve(Some("abc-123"));
+        assert_eq!(generated.len(), 32);
+        assert!(generated.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(generated, ids.resolve(Some("abc-123")));
+
+        let trusted = RequestIds {
+            trust: true,
+            ..Default::default()
+        };
+        assert_eq!(trusted.resolve(Some("abc-123")), "abc-123");
+        for invalid in ["", "two words", "line\nbreak", &"a".repeat(201)] {
+            assert_ne!(trusted.resolve(Some(invalid)), invalid);
+        }
+    }
+
+    #[test]
+    fn echoes_id_sent_upstream() {
+        let ids = RequestIds {
+            header: "X-Correlation-Id".into(),
+            trust: false,
+        };
+        let request = RequestHead {
+            method: "GET".into(),
+            target: "/".into(),
+            version: 1,
+            headers: vec![header("x-correlation-id", "abc")],
+        };
+        let mut response = ResponseHead {
+            version: 1,
+            status: 200,
+            reason: "OK".into(),
+            headers: vec![header("X-Correlation-Id", "from upstream")],
+        };
+        ids.echo(&request, &mut response);
+        assert_eq!(response.headers.len(), 1);
+        assert_eq!(response.headers[0].value, "abc");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-correlation-id", "abc".parse().unwrap());
+        assert_eq!(ids.echoed(&headers), headers);
+        assert!(ids.echoed(&HeaderMap::new()).is_empty());
+    }
+}
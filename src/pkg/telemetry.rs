@@ -0,0 +1,377 @@
+use std::{cell::RefCell, future::Future};
+
+use config::ConfigError;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{TraceContextExt, TraceError, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Config, Sampler, TracerProvider},
+    Resource,
+};
+use tracing::{field::Empty, Level, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::Targets, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+use crate::{
+    pkg::{conf::settings, server::http::RequestHead, spec::routes::UpstreamTarget},
+    prelude::{ProxyError, Result},
+};
+
+/// Sets up logging, and exporting spans over OTLP if `OTLP_ENDPOINT` is set. The
+/// returned provider has to be shut down to flush spans still queued on exit.
+pub fn init() -> Result<Option<TracerProvider>> {
+    let logs = logs()?;
+    let Some(ref endpoint) = settings.otlp_endpoint else {
+        tracing_subscriber::registry().with(logs).init();
+        return Ok(None);
+    };
+    let ratio = settings.otlp_sampling_ratio.unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(
+            TraceError::from(format!("sampling ratio {} not within 0 and 1", ratio)).into(),
+        );
+    }
+    let service = settings
+        .otlp_service_name
+        .clone()
+        .unwrap_or("liteginx".into());
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            Config::default()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    ratio,
+                ))))
+                .with_resource(Resource::new([KeyValue::new("service.name", service)])),
+        )
+        .install_batch(runtime::Tokio)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    // only our own spans, the exporter's would feed back into it
+    let spans = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("liteginx"))
+        .with_filter(Targets::new().with_target("liteginx", Level::INFO));
+    tracing_subscriber::registry().with(logs).with(spans).init();
+    tracing::info!("exporting traces to {}", endpoint);
+    Ok(Some(provider))
+}
+
+/// Log lines at `LOG_LEVEL`, or as `RUST_LOG` says if unset, written as JSON
+/// or as plain text depending on `LOG_FORMAT`.
+fn logs<S>() -> Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let invalid = |e: String| ProxyError::SettingsError(ConfigError::Message(e));
+    let filter = match settings.log_level {
+        Some(ref level) => EnvFilter::try_new(level)
+            .map_err(|e| invalid(format!("invalid log level {}: {}", level, e)))?,
+        None => EnvFilter::from_default_env(),
+    };
+    let logs = match settings.log_format.as_deref() {
+        None | Some("pretty") => tracing_subscriber::fmt::layer().boxed(),
+        Some("json") => tracing_subscriber::fmt::layer().json().boxed(),
+        Some(format) => return Err(invalid(format!("unknown log format {}", format))),
+    };
+    Ok(logs.with_filter(filter).boxed())
+}
+
+/// The span of a connection accepted on `listen`, for as long as it is open.
+pub fn connection_span(listen: u16, client: &str) -> Span {
+    tracing::info_span!(
+        "connection",
+        otel.kind = "server",
+        server.port = listen,
+        client.address = client,
+    )
+}
+
+/// The span of one HTTP request, continuing the trace of the client's
+/// `traceparent` if it sent one. Route, target and status are recorded as they
+/// become known.
+pub fn request_span(
+    listen: u16,
+    method: &str,
+    path: &str,
+    request_id: &str,
+    parent: Context,
+) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        otel.name = method,
+        otel.kind = "server",
+        request.id = request_id,
+        server.port = listen,
+        http.request.method = method,
+        url.path = path,
+        http.route = Empty,
+        upstream.address = Empty,
+        http.response.status_code = Empty,
+    );
+    if parent.span().span_context().is_valid() {
+        span.set_parent(parent);
+    }
+    span
+}
+
+/// What became known about a request or TCP session while it was handled, for
+/// metrics and access logs.
+#[derive(Debug, Default, Clone)]
+pub struct RequestRecord {
+    pub host: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub protocol: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub rewritten: Option<String>,
+    pub route: Option<String>,
+    pub target: Option<String>,
+    pub status: Option<u16>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+tokio::task_local! {
+    static RECORD: RefCell<RequestRecord>;
+}
+
+/// Runs `handle` with a record to fill in, taken with `take_record` once a
+/// request or session is done.
+pub async fn recording<F: Future>(handle: F) -> F::Output {
+    RECORD.scope(RefCell::default(), handle).await
+}
+
+/// What was recorded since the last call, starting afresh for the next request
+/// on the connection.
+pub fn take_record() -> RequestRecord {
+    RECORD.try_with(|record| record.take()).unwrap_or_default()
+}
+
+fn record(update: impl FnOnce(&mut RequestRecord)) {
+    let _ = RECORD.try_with(|record| update(&mut record.borrow_mut()));
+}
+
+/// Records the request line and the headers access logs show.
+pub fn record_request(
+    method: &str,
+    path: &str,
+    protocol: &str,
+    authority: Option<&str>,
+    headers: &dyn Extractor,
+) {
+    record(|record| {
+        record.host = authority.or(headers.get("host")).map(String::from);
+        record.method = Some(method.to_string());
+        record.path = Some(path.to_string());
+        record.protocol = Some(protocol.to_string());
+        record.referer = headers.get("referer").map(String::from);
+        record.user_agent = headers.get("user-agent").map(String::from);
+    });
+}
+
+/// Records the server name a TLS passthrough session was routed by.
+pub fn record_host(host: Option<&str>) {
+    record(|record| record.host = host.map(String::from));
+}
+
+pub fn record_rewrite(path: &str) {
+    record(|record| record.rewritten = Some(path.to_string()));
+}
+
+pub fn record_received(n: usize) {
+    record(|record| record.bytes_received += n as u64);
+}
+
+pub fn record_sent(n: usize) {
+    record(|record| record.bytes_sent += n as u64);
+}
+
+pub fn record_route(method: &str, route: &str) {
+    let span = Span::current();
+    span.record("otel.name", format!("{} {}", method, route));
+    span.record("http.route", route);
+    record(|record| record.route = Some(route.to_string()));
+}
+
+pub fn record_target(target: &UpstreamTarget) {
+    let address = format!("{}:{}", &target.host, &target.port);
+    Span::current().record("upstream.address", &address);
+    record(|record| record.target = Some(address));
+}
+
+pub fn record_status(status: u16) {
+    Span::current().record("http.response.status_code", status);
+    record(|record| record.status = Some(status));
+}
+
+/// The trace context a client sent along with a request.
+pub fn extract(headers: &dyn Extractor) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(headers))
+}
+
+/// Adds the trace context of the current span to a request sent upstream,
+/// replacing whatever the client sent.
+pub fn inject(headers: &mut dyn Injector) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, headers));
+}
+
+impl Extractor for RequestHead {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.header(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.headers.iter().map(|h| h.name.as_str()).collect()
+    }
+}
+
+impl Injector for RequestHead {
+    fn set(&mut self, key: &str, value: String) {
+        self.set_header(key, &value);
+    }
+}
+
+/// Carries trace context in and out of HTTP/2 headers.
+pub struct Headers<'a>(pub &'a mut HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+impl Injector for Headers<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TraceId;
+    use tracing_subscriber::Registry;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::pkg::server::http::Header;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const CLIENT_PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn head(headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".into(),
+            target: "/".into(),
+            version: 1,
+            headers: headers
+                .iter()
+                .map(|(name, value)| Header {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Runs `f` with request spans exported to an in-process tracer.
+    fn traced<T>(f: impl FnOnce() -> T) -> T {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder().build();
+        let subscriber = Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, f)
+    }
+
+    /// The trace and parent span ids of a `traceparent` value.
+    fn ids(traceparent: &str) -> (&str, &str) {
+        let parts = traceparent.split('-').collect::<Vec<_>>();
+        (parts[1], parts[2])
+    }
+
+    #[test]
+    fn continues_client_trace_upstream() {
+        let client = head(&[("traceparent", CLIENT_PARENT)]);
+        let mut upstream = client.clone();
+        let span = traced(|| {
+            let span = request_span(8080, "GET", "/", "id", extract(&client));
+            span.in_scope(|| inject(&mut upstream));
+            span
+        });
+        assert_eq!(
+            span.context().span().span_context().trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        // the upstream request is a child of the request span, not of the client's
+        let traceparent = upstream.header("traceparent").unwrap();
+        let (trace, parent) = ids(traceparent);
+        assert_eq!(trace, TRACE_ID);
+        assert_ne!(parent, ids(CLIENT_PARENT).1);
+        assert_eq!(
+            upstream
+                .headers
+                .iter()
+                .filter(|h| h.name == "traceparent")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn starts_trace_without_client_context() {
+        let client = head(&[("traceparent", "not a trace")]);
+        let mut upstream = client.clone();
+        let mut headers = HeaderMap::new();
+        traced(|| {
+            let span = request_span(8080, "GET", "/", "id", extract(&client));
+            span.in_scope(|| {
+                inject(&mut upstream);
+                inject(&mut Headers(&mut headers));
+            });
+        });
+        let traceparent = upstream.header("traceparent").unwrap();
+        assert_ne!(traceparent, "not a trace");
+        assert_ne!(ids(traceparent).0, TRACE_ID);
+        // HTTP/2 requests carry the same context
+        assert_eq!(
+            headers.get("traceparent").and_then(|v| v.to_str().ok()),
+            Some(traceparent)
+        );
+        let extracted = extract(&Headers(&mut headers));
+        assert_eq!(
+            extracted.span().span_context().trace_id().to_string(),
+            ids(traceparent).0
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn logs_request_id() {
+        let span = request_span(8080, "GET", "/api", "abc-123", Context::new());
+        span.in_scope(|| tracing::info!("relaying request"));
+        assert!(logs_contain("request.id=\"abc-123\""));
+        assert!(logs_contain("relaying request"));
+    }
+}
@@ -1,14 +1,38 @@
 use thiserror::Error;
-use tokio::sync::mpsc;
 
 pub type Result<T> = core::result::Result<T, ProxyError>;
 
+/// An error followed by its causes, as most variants only name the kind of
+/// failure. Causes already spelled out in the message are left out.
+pub fn describe(e: &dyn std::error::Error) -> String {
+    let mut described = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        let cause_text = cause.to_string();
+        if !described.ends_with(&cause_text) {
+            described = format!("{}: {}", described, cause_text);
+        }
+        source = cause.source();
+    }
+    described
+}
+
 #[derive(Error, Debug)]
 pub enum ProxyError {
     #[error("generic error")]
     Generic,
+    #[error("no confs given, pass --conf-dir or --conf, or set LITEGINX_CONF_DIR")]
+    NoConfDir,
+    #[error("invalid conf: {0}")]
+    InvalidConf(String),
+    #[error("no route listens on port {0}")]
+    NoRouteOnPort(u16),
     #[error("empty targets, cannot start downstream server")]
     DownStreamServerEmptyTargets,
+    #[error("no healthy upstream targets in rotation")]
+    NoHealthyTargets,
+    #[error("upstream target disabled")]
+    TargetDisabled,
     #[error("error connecting to upstream target")]
     UpstreamConnectionRefused(String),
     #[error("error sending message downstream")]
@@ -25,12 +49,75 @@ pub enum ProxyError {
     DownStreamEndOfBytes,
     #[error("end of bytes received from upstream")]
     UpStreamEndOfBytes,
+    #[error("timed out connecting to upstream target")]
+    UpstreamConnectTimeout,
+    #[error("timed out waiting for upstream response")]
+    UpstreamResponseTimeout,
+    #[error("timed out reading request header from client")]
+    ClientHeaderTimeout,
+    #[error("connection idle timeout")]
+    IdleTimeout,
+    #[error("listener handoff failed: {0}")]
+    HandoffFailed(String),
+    #[error("shutting down")]
+    ShuttingDown,
+    #[error("malformed http message: {0}")]
+    MalformedHttp(String),
+    #[error("invalid forward conf: {0}")]
+    InvalidForwardConf(String),
+    #[error("forwarding to {0} not allowed")]
+    ForwardDenied(String),
+    #[error("upgrade not supported by upstream target")]
+    UpgradeUnsupported,
+    #[error("malformed tls handshake: {0}")]
+    MalformedTls(String),
+    #[error("invalid PROXY protocol header: {0}")]
+    InvalidProxyHeader(String),
+    #[error("invalid tls conf: {0}")]
+    InvalidTlsConf(String),
+    #[error("client {0} not allowed")]
+    ClientNotAllowed(String),
+    #[error("invalid access log conf: {0}")]
+    InvalidAccessLogConf(String),
+    #[error("acme error: {0}")]
+    AcmeError(String),
+    #[error("trace export error")]
+    TraceError(#[from] opentelemetry::trace::TraceError),
+    #[error("tls error")]
+    TlsError(#[from] rustls::Error),
+    #[error("http2 stream closed by peer")]
+    StreamClosed,
+    #[error("http2 error")]
+    Http2Error(#[from] h2::Error),
+    #[error("invalid http message")]
+    HttpError(#[from] http::Error),
     #[error("io error")]
     IoError(#[from] std::io::Error),
     #[error("json decode error")]
     JSONDecodeError(#[from] serde_json::Error),
+    #[error("invalid settings: {0}")]
+    SettingsError(#[from] config::ConfigError),
     #[error("invalid time format error")]
     DurationError(#[from] humantime::DurationError),
-    #[error("error writing to channel")]
-    ChannelWriteError(#[from] mpsc::error::SendError<Vec<u8>>),
+}
+
+#[cfg(test)]
+mod tests {
+    use config::ConfigError;
+
+    use super::*;
+
+    #[test]
+    fn describes_error_chain() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "fixtures/none.yaml");
+        assert_eq!(
+            describe(&ProxyError::from(io)),
+            "io error: fixtures/none.yaml"
+        );
+        let settings = ConfigError::Message("unknown log format xml".into());
+        assert_eq!(
+            describe(&ProxyError::from(settings)),
+            "invalid settings: unknown log format xml"
+        );
+    }
 }